@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zpng_rs_lib::{ZPNG_Buffer, ZPNG_Decompress};
+
+// any input has to be turned into either an image or an error, never a panic or an abort, with
+// the same default limits as every other caller
+fuzz_target!(|data: &[u8]| {
+    let _ = ZPNG_Decompress(&ZPNG_Buffer {
+        Data: data.to_vec(),
+    });
+});
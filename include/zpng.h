@@ -0,0 +1,132 @@
+/* C API of zpng_rs, a port of https://github.com/catid/Zpng with the same functions as its zpng.h */
+
+#ifndef ZPNG_H
+#define ZPNG_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit */
+
+/*
+ Magic of the original header with 16 bit dimensions, compatible with the reference Zpng
+ */
+#define ZPNG_HEADER_MAGIC 64504
+
+#define ZPNG_HEADER_OVERHEAD_BYTES 8
+
+/*
+ Magic of the header with 32 bit dimensions and flags
+ */
+#define ZPNG_HEADER_MAGIC_V2 64505
+
+#define ZPNG_HEADER_V2_OVERHEAD_BYTES 16
+
+/*
+ Header flag, 2 byte channels were filtered as 16 bit samples and stored as high and low byte
+ planes
+ */
+#define ZPNG_FLAG_16BIT_SAMPLES 1
+
+/*
+ Header flag, 4 byte channels were XOR filtered as 32 bit samples and stored as byte planes
+ */
+#define ZPNG_FLAG_32BIT_SAMPLES 2
+
+/*
+ Header flag, the image was transformed by a custom `Filter`, whose ID is stored in place of
+ the color filter and predictor
+ */
+#define ZPNG_FLAG_CUSTOM_FILTER 4
+
+/*
+ Buffer returned by the library
+ */
+typedef struct ZPNG_Buffer {
+  /*
+   Pointer to data
+   */
+  unsigned char *Data;
+  /*
+   Size of buffer in bytes
+   */
+  unsigned int Bytes;
+} ZPNG_Buffer;
+
+/*
+ Image data passed to ZPNG_Compress() or returned by ZPNG_Decompress()
+ */
+typedef struct ZPNG_ImageData {
+  /*
+   Pixel data
+   */
+  struct ZPNG_Buffer Buffer;
+  /*
+   Number of bytes for each color channel (1, 2 or 4), wider samples are little endian
+   */
+  unsigned int BytesPerChannel;
+  /*
+   Number of channels for each pixel (1-4, or 1-8 for 1 byte channels)
+   */
+  unsigned int Channels;
+  /*
+   Width in pixels of image
+   */
+  unsigned int WidthPixels;
+  /*
+   Height in pixels of image
+   */
+  unsigned int HeightPixels;
+  /*
+   Width of pixel row in bytes, 0 for rows without padding
+   */
+  unsigned int StrideBytes;
+} ZPNG_ImageData;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Compress image into a buffer.
+
+ Writes what the reference Zpng writes, so 16 bit channels are filtered byte by byte.
+
+ The returned buffer should be passed to ZPNG_Free().
+
+ On success returns a valid data pointer.
+ On failure returns a null pointer.
+
+ # Safety
+
+ `imageData` has to be null or point to an image whose `Buffer` holds at least `Bytes` bytes.
+ */
+struct ZPNG_Buffer ZPNG_Compress(const struct ZPNG_ImageData *imageData);
+
+/*
+ Decompress image from a buffer.
+
+ The returned ZPNG_Buffer should be passed to ZPNG_Free().
+
+ On success returns a valid data pointer.
+ On failure returns a null pointer.
+
+ # Safety
+
+ `buffer.Data` has to be null or point to at least `buffer.Bytes` bytes.
+ */
+struct ZPNG_ImageData ZPNG_Decompress(struct ZPNG_Buffer buffer);
+
+/*
+ Free buffer when done to avoid leaks.
+
+ This will also set the buffer to null.
+
+ # Safety
+
+ `buffer` has to be null or point to a buffer returned by this library, which was not changed.
+ */
+void ZPNG_Free(struct ZPNG_Buffer *buffer);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* ZPNG_H */
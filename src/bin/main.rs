@@ -5,21 +5,42 @@
 #![feature(const_generics)]
 
 use argh::FromArgs;
+use image::codecs::{
+    bmp::BmpEncoder, ico::IcoEncoder, png::PngEncoder, tga::TgaEncoder, tiff::TiffEncoder,
+};
+use image::{ColorType, DynamicImage, GenericImageView, ImageEncoder, ImageFormat};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Cursor, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
-use zpng_rs_lib::{ZPNG_Buffer, ZPNG_Compress, ZPNG_Decompress, ZPNG_ImageData};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use zpng_rs_lib::{
+    CompressOptions, ZPNG_Buffer, ZPNG_Compress, ZPNG_CompressWithOptions, ZPNG_Decompress,
+    ZPNG_Header, ZPNG_ImageData, ZPNG_ReadHeader, ZpngDecoder, ZPNG_FLAG_16BIT_SAMPLES,
+    ZPNG_FLAG_32BIT_SAMPLES, ZPNG_FLAG_CUSTOM_FILTER, ZPNG_HEADER_MAGIC_V2,
+    ZPNG_HEADER_V2_OVERHEAD_BYTES,
+};
+
+/// Path standing for stdin as input and stdout as output
+const Stdio: &str = "-";
 
 // TODO it would be nice to use a crate that would allow mutually exclusive options (-c -d)
 // clap has this feature (and thus structopt probably as well but structopt/ clap gave me linker errors at the time of writing)
 #[derive(FromArgs)]
 /// Zpng_rs - Experimental Lossless Image Compressor
 struct Opt {
-    /// compress an image (jpeg, webp, tga, bmp, png, gif, ico), saves as .zpng
+    /// compress an image (jpeg, webp, tga, bmp, png, gif, ico, tiff), saves as .zpng with the
+    /// channels and bit depth of the input
     #[argh(switch, short = 'c')]
     compress: bool,
 
-    /// decompress a .zpng image, saves as .png
+    /// decompress a .zpng image, saves as .png unless --format or the extension of the output
+    /// file says otherwise
     #[argh(switch, short = 'd')]
     decompress: bool,
 
@@ -33,89 +54,152 @@ struct Opt {
     #[argh(switch)]
     test: bool,
 
-    /// input file
+    /// input file or - for stdin, can be repeated and can be a glob like 'shots/**/*.png' or a
+    /// directory together with --recursive
     #[argh(option, short = 'i')]
-    inpath: String,
+    inpath: Vec<String>,
 
-    /// output file, deduced to be the input filename with .png
+    /// output file or - for stdout, deduced to be the input filename with .zpng or the extension
+    /// of the decompressed format, stdout for stdin. With several inputs this is the directory the tree is mirrored into,
+    /// without it the files are written next to their inputs
     #[argh(option, short = 'o')]
     outpath: Option<String>,
+
+    /// convert all images below the input directories
+    #[argh(switch, short = 'r')]
+    recursive: bool,
+
+    /// number of files converted at the same time, defaults to the number of cores
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+
+    /// format to decompress to (png, tiff, bmp, tga, webp, ico), instead of the one of the output
+    /// file extension. -c can't read the lossless webp back in
+    #[argh(option)]
+    format: Option<String>,
+
+    /// filter 16 bit channels as whole samples when compressing, which gives smaller files that
+    /// only zpng_rs can read
+    #[argh(switch)]
+    filter16: bool,
+
+    #[argh(subcommand)]
+    info: Option<Info>,
+}
+
+#[derive(FromArgs)]
+/// print the header and compression statistics of .zpng files without decompressing them
+#[argh(subcommand, name = "info")]
+struct Info {
+    /// zpng files, stdin if there are none
+    #[argh(positional)]
+    files: Vec<String>,
+
+    /// print one JSON object per file instead
+    #[argh(switch)]
+    json: bool,
 }
 
 fn main() {
-    if cfg!(feature = "color_backtrace") {
+    // the crate is only there with the feature, so this can't be a cfg! check
+    #[cfg(feature = "color-backtrace")]
+    {
         std::env::set_var("RUST_BACKTRACE", "full");
         color_backtrace::install();
     }
 
     let opt: Opt = argh::from_env();
-    let inpath = opt.inpath;
-    let mut outpath = opt.outpath;
+    let outpath = opt.outpath;
 
-    if opt.compress as u8 + opt.decompress as u8 + opt.test as u8 > 1 {
-        println!("ERROR: --compress, --decompress and --test are mutually exclusive");
+    if let Some(mut info) = opt.info {
+        if info.files.is_empty() {
+            info.files.push(Stdio.to_string());
+        }
+        if !PrintInfo(&info.files, info.json) {
+            std::process::exit(1);
+        }
         return;
     }
 
-    if opt.compress {
-        if outpath.is_none() {
-            outpath = Some(
-                Path::new(&inpath)
-                    .with_extension("zpng")
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-            );
-        }
+    if opt.compress as u8 + opt.decompress as u8 + opt.test as u8 > 1 {
+        eprintln!("ERROR: --compress, --decompress and --test are mutually exclusive");
+        std::process::exit(1);
+    }
+    if opt.inpath.is_empty() {
+        eprintln!("ERROR: no input file, pass one with -i");
+        std::process::exit(1);
+    }
 
-        // TODO don't always convert to rgba, if this is rgb this is not needed
-        let image = image::open(&inpath).unwrap().to_rgba();
-        let (width, height) = image.dimensions();
+    // several files, globs and directories go into a directory, otherwise -o is the file
+    let batch = opt.recursive
+        || opt.inpath.len() > 1
+        || opt
+            .inpath
+            .iter()
+            .any(|input| IsGlob(input) || Path::new(input).is_dir());
 
-        let image_data = ZPNG_ImageData {
-            Buffer: ZPNG_Buffer {
-                Data: image.into_raw(),
+    if opt.compress || opt.decompress {
+        // zpng when compressing, otherwise the flag wins over the extension and without either
+        // it is png
+        let extension = match (&opt.format, &outpath) {
+            _ if opt.compress => "zpng".to_string(),
+            (Some(format), _) => format.to_lowercase(),
+            (None, Some(path)) if !batch => match Path::new(path).extension() {
+                Some(extension) => extension.to_string_lossy().to_lowercase(),
+                None => "png".to_string(),
             },
-            // TODO deduce this from image open metadata
-            BytesPerChannel: 1,
-            // TODO deduce this from image open metadata
-            Channels: 4,
-            WidthPixels: width as u16,
-            HeightPixels: height as u16,
+            _ => "png".to_string(),
         };
-        let comp = ZPNG_Compress(&image_data).unwrap();
-
-        fs::write(outpath.unwrap(), &comp.Data).unwrap();
-    } else if opt.decompress {
-        if outpath.is_none() {
-            outpath = Some(
-                Path::new(&inpath)
-                    .with_extension("png")
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-            );
-        }
-
-        let buffer = fs::read(&inpath).unwrap();
-        let dec = ZPNG_Decompress(&ZPNG_Buffer { Data: buffer }).unwrap();
-
-        let format = match dec.Channels {
-            3 => image::ColorType::Rgb8,
-            4 => image::ColorType::Rgba8,
-            _ => unimplemented!(),
+        let mode = if opt.compress {
+            Mode::Compress(CompressOptions {
+                Filter16Bit: opt.filter16,
+                ..CompressOptions::default()
+            })
+        } else {
+            match OutputFormat(&extension) {
+                Ok(format) => Mode::Decompress(format),
+                Err(message) => {
+                    eprintln!("ERROR: {}", message);
+                    std::process::exit(1);
+                }
+            }
         };
 
-        image::save_buffer(
-            outpath.unwrap(),
-            &dec.Buffer.Data,
-            dec.WidthPixels as u32,
-            dec.HeightPixels as u32,
-            format,
-        )
-        .unwrap();
+        if batch {
+            let jobs = opt.jobs.unwrap_or_else(num_cpus::get).max(1);
+            let outdir = outpath.as_ref().map(Path::new);
+            // the files already keep the cores busy, one zstd worker each is enough
+            let mode = match mode {
+                Mode::Compress(options) => Mode::Compress(CompressOptions {
+                    Threads: 1,
+                    ..options
+                }),
+                mode => mode,
+            };
+            if !Batch(&opt.inpath, opt.recursive, outdir, mode, &extension, jobs) {
+                std::process::exit(1);
+            }
+        } else {
+            let inpath = Path::new(&opt.inpath[0]);
+            let outpath = match outpath {
+                Some(outpath) => PathBuf::from(outpath),
+                // what comes from a pipe goes on to one
+                None if inpath == Path::new(Stdio) => PathBuf::from(Stdio),
+                None => inpath.with_extension(&extension),
+            };
+            if let Err(message) = Convert(inpath, &outpath, mode) {
+                eprintln!("ERROR: {}", message);
+                std::process::exit(1);
+            }
+        }
     } else if opt.test {
-        let buffer = image::open(inpath.clone()).unwrap().to_rgba();
+        if opt.inpath.len() != 1 {
+            eprintln!("ERROR: --test takes a single input file");
+            std::process::exit(1);
+        }
+        let inpath = opt.inpath[0].clone();
+
+        let buffer = image::open(inpath.clone()).unwrap().to_rgba8();
         let (width, height) = buffer.dimensions();
 
         let image_data = ZPNG_ImageData {
@@ -124,8 +208,8 @@ fn main() {
             },
             BytesPerChannel: 1,
             Channels: 4,
-            WidthPixels: width as u16,
-            HeightPixels: height as u16,
+            WidthPixels: width,
+            HeightPixels: height,
         };
 
         // makes sure that it can decompress the image without writing it to disc
@@ -147,7 +231,7 @@ fn main() {
             .success());
 
         // write own output
-        let image = image::open(&inpath).unwrap().to_rgba();
+        let image = image::open(&inpath).unwrap().to_rgba8();
         let (width, height) = image.dimensions();
 
         let image_data = ZPNG_ImageData {
@@ -156,8 +240,8 @@ fn main() {
             },
             BytesPerChannel: 1,
             Channels: 4,
-            WidthPixels: width as u16,
-            HeightPixels: height as u16,
+            WidthPixels: width,
+            HeightPixels: height,
         };
         let comp = ZPNG_Compress(&image_data).unwrap();
 
@@ -191,10 +275,552 @@ fn main() {
         image::save_buffer(
             rust_png,
             &dec.Buffer.Data,
-            dec.WidthPixels as u32,
-            dec.HeightPixels as u32,
+            dec.WidthPixels,
+            dec.HeightPixels,
             format,
         )
         .unwrap();
     }
 }
+
+/// Keeps the channels and bit depth of the source image, so decompressing gives back the same
+/// pixels
+fn ToImageData(image: DynamicImage) -> ZPNG_ImageData {
+    let (width, height) = image.dimensions();
+    let (channels, bytesPerChannel, data) = match image {
+        DynamicImage::ImageLuma8(image) => (1, 1, image.into_raw()),
+        DynamicImage::ImageLumaA8(image) => (2, 1, image.into_raw()),
+        DynamicImage::ImageRgb8(image) => (3, 1, image.into_raw()),
+        DynamicImage::ImageRgba8(image) => (4, 1, image.into_raw()),
+        // zpng has no BGR order, the color filters expect RGB
+        image @ DynamicImage::ImageBgr8(_) => (3, 1, image.to_rgb8().into_raw()),
+        image @ DynamicImage::ImageBgra8(_) => (4, 1, image.to_rgba8().into_raw()),
+        DynamicImage::ImageLuma16(image) => (1, 2, LittleEndian(image.into_raw())),
+        DynamicImage::ImageLumaA16(image) => (2, 2, LittleEndian(image.into_raw())),
+        DynamicImage::ImageRgb16(image) => (3, 2, LittleEndian(image.into_raw())),
+        DynamicImage::ImageRgba16(image) => (4, 2, LittleEndian(image.into_raw())),
+    };
+
+    ZPNG_ImageData {
+        Buffer: ZPNG_Buffer { Data: data },
+        BytesPerChannel: bytesPerChannel,
+        Channels: channels,
+        WidthPixels: width,
+        HeightPixels: height,
+    }
+}
+
+/// zpng stores 16 bit samples little endian
+fn LittleEndian(samples: Vec<u16>) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Image format for an extension or format name, only formats that can store the pixels without
+/// loss are accepted
+fn OutputFormat(name: &str) -> Result<ImageFormat, String> {
+    match ImageFormat::from_extension(name) {
+        Some(format)
+            if matches!(
+                format,
+                ImageFormat::Png
+                    | ImageFormat::Tiff
+                    | ImageFormat::Bmp
+                    | ImageFormat::Tga
+                    | ImageFormat::WebP
+                    | ImageFormat::Ico
+            ) =>
+        {
+            Ok(format)
+        }
+        Some(format) => Err(format!("{:?} would not keep the image lossless", format)),
+        None => Err(format!("unknown output format {}", name)),
+    }
+}
+
+/// Whether `format` can store images of `color` without changing any pixel
+fn Lossless(format: ImageFormat, color: ColorType) -> bool {
+    use ColorType::*;
+    match format {
+        ImageFormat::Png => true,
+        ImageFormat::Tiff => !matches!(color, La8 | La16),
+        ImageFormat::Bmp => matches!(color, L8 | Rgb8 | Rgba8),
+        ImageFormat::Tga | ImageFormat::WebP | ImageFormat::Ico => {
+            matches!(color, L8 | La8 | Rgb8 | Rgba8)
+        }
+        _ => false,
+    }
+}
+
+/// Encodes the decompressed image, only if `format` keeps every pixel
+fn Encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    if !Lossless(format, image.color()) {
+        return Err(format!(
+            "{:?} can't store {:?} images without loss, use png instead",
+            format,
+            image.color()
+        ));
+    }
+    if format == ImageFormat::WebP {
+        return EncodeWebP(image);
+    }
+
+    // icons hold RGBA, readers tend to refuse other PNGs inside
+    let rgba;
+    let image = match format {
+        ImageFormat::Ico => {
+            rgba = DynamicImage::ImageRgba8(image.to_rgba8());
+            &rgba
+        }
+        _ => image,
+    };
+    let (width, height) = image.dimensions();
+    let (data, color) = (image.as_bytes(), image.color());
+
+    // the tiff encoder seeks
+    let mut output = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Png => PngEncoder::new(&mut output).write_image(data, width, height, color),
+        ImageFormat::Tiff => TiffEncoder::new(&mut output).write_image(data, width, height, color),
+        ImageFormat::Bmp => BmpEncoder::new(&mut output).write_image(data, width, height, color),
+        ImageFormat::Tga => TgaEncoder::new(&mut output).write_image(data, width, height, color),
+        ImageFormat::Ico => IcoEncoder::new(&mut output).write_image(data, width, height, color),
+        format => return Err(format!("can't write {:?}", format)),
+    }
+    .map_err(|err| err.to_string())?;
+    Ok(output.into_inner())
+}
+
+/// The image crate can only decode WebP, image-webp writes it lossless
+#[cfg(feature = "image-webp")]
+fn EncodeWebP(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let color = match image.color() {
+        ColorType::L8 => image_webp::ColorType::L8,
+        ColorType::La8 => image_webp::ColorType::La8,
+        ColorType::Rgb8 => image_webp::ColorType::Rgb8,
+        ColorType::Rgba8 => image_webp::ColorType::Rgba8,
+        color => return Err(format!("WebP can't store {:?} images", color)),
+    };
+
+    let mut output = Vec::new();
+    let (width, height) = image.dimensions();
+    image_webp::WebPEncoder::new(&mut output)
+        .encode(image.as_bytes(), width, height, color)
+        .map_err(|err| err.to_string())?;
+    Ok(output)
+}
+
+#[cfg(not(feature = "image-webp"))]
+fn EncodeWebP(_image: &DynamicImage) -> Result<Vec<u8>, String> {
+    Err("built without the image-webp feature, which writes WebP".to_string())
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Compress(CompressOptions),
+    Decompress(ImageFormat),
+}
+
+/// Compresses an image to zpng or decompresses a zpng file to `format`, `-` reads from stdin or
+/// writes to stdout
+fn Convert(inpath: &Path, outpath: &Path, mode: Mode) -> Result<(), String> {
+    let stdin = inpath == Path::new(Stdio);
+    let output = match mode {
+        Mode::Compress(options) => {
+            // without a file extension the format has to be guessed from the first bytes
+            let image = if stdin {
+                let mut input = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut input)
+                    .map_err(|err| err.to_string())?;
+                image::load_from_memory(&input)
+            } else {
+                image::open(inpath)
+            }
+            .map_err(|err| err.to_string())?;
+            ZPNG_CompressWithOptions(&ToImageData(image), &options)
+                .map_err(|err| err.to_string())?
+                .Data
+        }
+        Mode::Decompress(format) => {
+            let decoder = if stdin {
+                ZpngDecoder::new(io::stdin())
+            } else {
+                let file = fs::File::open(inpath).map_err(|err| err.to_string())?;
+                ZpngDecoder::new(io::BufReader::new(file))
+            }
+            .map_err(|err| err.to_string())?;
+            let image = DynamicImage::from_decoder(decoder).map_err(|err| err.to_string())?;
+            Encode(&image, format)?
+        }
+    };
+
+    if outpath == Path::new(Stdio) {
+        io::stdout().write_all(&output)
+    } else {
+        fs::write(outpath, &output)
+    }
+    .map_err(|err| err.to_string())
+}
+
+/// Converts all inputs on `jobs` threads, a file that fails is reported and the others go on.
+/// Returns whether every file was converted.
+fn Batch(
+    inputs: &[String],
+    recursive: bool,
+    outdir: Option<&Path>,
+    mode: Mode,
+    extension: &str,
+    jobs: usize,
+) -> bool {
+    let start = Instant::now();
+    let mut files = Vec::new();
+    let mut failed = 0;
+    for input in inputs {
+        if let Err(message) = Collect(input, recursive, mode, &mut files) {
+            eprintln!("ERROR: {}: {}", input, message);
+            failed += 1;
+        }
+    }
+
+    // the tree below each input is recreated in the output directory, inputs that would still
+    // be written to the same file are refused instead of overwriting each other
+    let mut outpaths: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut unique = Vec::with_capacity(files.len());
+    for (inpath, relative) in files {
+        let outpath = match outdir {
+            Some(outdir) => outdir.join(relative),
+            None => inpath.clone(),
+        }
+        .with_extension(extension);
+        if let Some(first) = outpaths.get(&outpath) {
+            eprintln!(
+                "ERROR: {}: would overwrite {}, the output of {}",
+                inpath.display(),
+                outpath.display(),
+                first.display()
+            );
+            failed += 1;
+            continue;
+        }
+        outpaths.insert(outpath.clone(), inpath.clone());
+        unique.push((inpath, outpath));
+    }
+    let files = unique;
+
+    let files = Arc::new(files);
+    let next = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..jobs.min(files.len()).max(1))
+        .map(|_| {
+            let files = Arc::clone(&files);
+            let next = Arc::clone(&next);
+            thread::spawn(move || {
+                let mut stats = Stats::default();
+                loop {
+                    let (inpath, outpath) = match files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        Some(file) => file,
+                        None => return stats,
+                    };
+                    match ConvertFile(inpath, outpath, mode) {
+                        Ok((inputBytes, outputBytes)) => {
+                            stats.Converted += 1;
+                            stats.InputBytes += inputBytes;
+                            stats.OutputBytes += outputBytes;
+                        }
+                        Err(message) => {
+                            eprintln!("ERROR: {}: {}", inpath.display(), message);
+                            stats.Failed += 1;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut stats = Stats {
+        Failed: failed,
+        ..Stats::default()
+    };
+    for worker in workers {
+        let worker = worker.join().unwrap();
+        stats.Converted += worker.Converted;
+        stats.Failed += worker.Failed;
+        stats.InputBytes += worker.InputBytes;
+        stats.OutputBytes += worker.OutputBytes;
+    }
+
+    println!(
+        "{} {} files, {} failed, {} -> {} bytes ({:.1} %) in {:.1} s",
+        match mode {
+            Mode::Compress(_) => "compressed",
+            Mode::Decompress(_) => "decompressed",
+        },
+        stats.Converted,
+        stats.Failed,
+        stats.InputBytes,
+        stats.OutputBytes,
+        100.0 * stats.OutputBytes as f64 / stats.InputBytes.max(1) as f64,
+        start.elapsed().as_secs_f64()
+    );
+    stats.Failed == 0
+}
+
+#[derive(Default)]
+struct Stats {
+    Converted: usize,
+    Failed: usize,
+    InputBytes: u64,
+    OutputBytes: u64,
+}
+
+/// `Convert` for one file of a batch, returns the sizes of input and output
+fn ConvertFile(inpath: &Path, outpath: &Path, mode: Mode) -> Result<(u64, u64), String> {
+    if let Some(parent) = outpath.parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+
+    // a decoder panicking on a broken file must not take down the whole batch
+    panic::catch_unwind(AssertUnwindSafe(|| Convert(inpath, outpath, mode)))
+        .map_err(|_| "panicked while converting".to_string())??;
+
+    let inputBytes = fs::metadata(inpath).map_err(|err| err.to_string())?.len();
+    let outputBytes = fs::metadata(outpath).map_err(|err| err.to_string())?.len();
+    Ok((inputBytes, outputBytes))
+}
+
+/// Adds the files of one input to `files`, together with the path they get in the output
+/// directory
+fn Collect(
+    input: &str,
+    recursive: bool,
+    mode: Mode,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), String> {
+    let path = Path::new(input);
+    if input == Stdio {
+        Err("stdin can only be read as the single input".to_string())
+    } else if IsGlob(input) {
+        // matches keep their path below the part of the pattern without wildcards
+        let base: PathBuf = path
+            .components()
+            .take_while(|component| !IsGlob(&component.as_os_str().to_string_lossy()))
+            .collect();
+        let matches = glob::glob(input).map_err(|err| err.to_string())?;
+        for path in matches {
+            let path = path.map_err(|err| err.to_string())?;
+            if path.is_file() && Wanted(&path, mode) {
+                let relative = path.strip_prefix(&base).unwrap_or(&path).to_path_buf();
+                files.push((path, relative));
+            }
+        }
+        Ok(())
+    } else if path.is_dir() {
+        if !recursive {
+            return Err("is a directory, pass --recursive to convert the files in it".to_string());
+        }
+        Walk(path, path, mode, files).map_err(|err| err.to_string())
+    } else {
+        // the directories are kept so files of the same name don't end up in the same place
+        let relative: PathBuf = path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        if relative.file_name().is_none() {
+            return Err("not a file".to_string());
+        }
+        files.push((path.to_path_buf(), relative));
+        Ok(())
+    }
+}
+
+fn Walk(
+    root: &Path,
+    dir: &Path,
+    mode: Mode,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            Walk(root, &path, mode, files)?;
+        } else if Wanted(&path, mode) {
+            let relative = path.strip_prefix(root).unwrap().to_path_buf();
+            files.push((path, relative));
+        }
+    }
+    Ok(())
+}
+
+/// Files found in directories or by globs are only taken if they can be converted
+fn Wanted(path: &Path, mode: Mode) -> bool {
+    let zpng = match path.extension() {
+        Some(extension) => extension.to_string_lossy().eq_ignore_ascii_case("zpng"),
+        None => false,
+    };
+    match mode {
+        Mode::Compress(_) => !zpng && ImageFormat::from_path(path).is_ok(),
+        Mode::Decompress(_) => zpng,
+    }
+}
+
+fn IsGlob(input: &str) -> bool {
+    input.contains(&['*', '?', '['][..])
+}
+
+/// Prints what the headers say about the files, only the header is read unless the file is stdin.
+/// Returns whether every file could be read.
+fn PrintInfo(files: &[String], json: bool) -> bool {
+    let mut ok = true;
+    for file in files {
+        match ReadInfo(file) {
+            Ok((header, fileBytes)) if json => println!("{}", InfoJson(file, &header, fileBytes)),
+            Ok((header, fileBytes)) => print!("{}", InfoText(file, &header, fileBytes)),
+            Err(message) => {
+                eprintln!("ERROR: {}: {}", file, message);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// The header and the size of the whole file
+fn ReadInfo(file: &str) -> Result<(ZPNG_Header, u64), String> {
+    let mut data = Vec::new();
+    let fileBytes = if file == Stdio {
+        io::stdin()
+            .read_to_end(&mut data)
+            .map_err(|err| err.to_string())? as u64
+    } else {
+        let file = fs::File::open(file).map_err(|err| err.to_string())?;
+        let fileBytes = file.metadata().map_err(|err| err.to_string())?.len();
+        file.take(ZPNG_HEADER_V2_OVERHEAD_BYTES as u64)
+            .read_to_end(&mut data)
+            .map_err(|err| err.to_string())?;
+        fileBytes
+    };
+
+    let header = ZPNG_ReadHeader(&data).map_err(|err| err.to_string())?;
+    Ok((header, fileBytes))
+}
+
+fn InfoText(file: &str, header: &ZPNG_Header, fileBytes: u64) -> String {
+    let raw = header.DecodedBytes();
+    let mut text = format!("{}\n", file);
+    text += &format!(
+        "  dimensions         {} x {}\n",
+        header.Width, header.Height
+    );
+    text += &format!("  channels           {}\n", header.Channels);
+    text += &format!("  bytes per channel  {}\n", header.BytesPerChannel);
+    text += &format!(
+        "  header             v{}, {} bytes\n",
+        Version(header),
+        header.OverheadBytes()
+    );
+    text += &format!("  flags              {:#04x}", header.Flags);
+    if header.Flags != 0 {
+        text += &format!(" ({})", FlagNames(header.Flags).join(", "));
+    }
+    text += "\n";
+    match header.CustomFilter {
+        Some(id) => text += &format!("  custom filter      {}\n", id),
+        None => {
+            text += &format!("  color filter       {:?}\n", header.ColorFilter);
+            text += &format!("  predictor          {:?}\n", header.Predictor);
+        }
+    }
+    text += &format!("  entropy backend    {:?}\n", header.EntropyBackend);
+    text += &format!("  compressed size    {} bytes\n", fileBytes);
+    text += &format!("  raw size           {} bytes\n", raw);
+    text += &format!(
+        "  ratio              {:.2} ({:.1} %)\n",
+        Ratio(raw, fileBytes),
+        100.0 * fileBytes as f64 / raw.max(1) as f64
+    );
+    text
+}
+
+/// No serde for a handful of fields, the file name is the only string that needs escaping
+fn InfoJson(file: &str, header: &ZPNG_Header, fileBytes: u64) -> String {
+    let names: Vec<String> = FlagNames(header.Flags)
+        .iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect();
+    let customFilter = match header.CustomFilter {
+        Some(id) => id.to_string(),
+        None => "null".to_string(),
+    };
+
+    format!(
+        concat!(
+            "{{\"file\":{},\"width\":{},\"height\":{},\"channels\":{},",
+            "\"bytes_per_channel\":{},\"version\":{},\"header_bytes\":{},\"flags\":{},",
+            "\"flag_names\":[{}],\"color_filter\":\"{:?}\",\"predictor\":\"{:?}\",",
+            "\"custom_filter\":{},\"entropy_backend\":\"{:?}\",\"compressed_bytes\":{},",
+            "\"raw_bytes\":{},\"ratio\":{:.4}}}"
+        ),
+        JsonString(file),
+        header.Width,
+        header.Height,
+        header.Channels,
+        header.BytesPerChannel,
+        Version(header),
+        header.OverheadBytes(),
+        header.Flags,
+        names.join(","),
+        header.ColorFilter,
+        header.Predictor,
+        customFilter,
+        header.EntropyBackend,
+        fileBytes,
+        header.DecodedBytes(),
+        Ratio(header.DecodedBytes(), fileBytes)
+    )
+}
+
+fn Version(header: &ZPNG_Header) -> u8 {
+    if header.Magic == ZPNG_HEADER_MAGIC_V2 {
+        2
+    } else {
+        1
+    }
+}
+
+fn FlagNames(flags: u8) -> Vec<&'static str> {
+    let names = [
+        (ZPNG_FLAG_16BIT_SAMPLES, "16bit-samples"),
+        (ZPNG_FLAG_32BIT_SAMPLES, "32bit-samples"),
+        (ZPNG_FLAG_CUSTOM_FILTER, "custom-filter"),
+    ];
+    names
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Raw size over compressed size
+fn Ratio(raw: u64, compressed: u64) -> f64 {
+    raw as f64 / compressed.max(1) as f64
+}
+
+fn JsonString(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            c if (c as u32) < 0x20 => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
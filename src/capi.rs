@@ -0,0 +1,204 @@
+//! C API with the same types and functions as `zpng.h` of the reference Zpng
+//!
+//! The header is `include/zpng.h`, generated by cbindgen from this file:
+//! `cbindgen --config cbindgen.toml --output include/zpng.h`
+
+use std::convert::TryFrom;
+use std::os::raw::{c_uchar, c_uint};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+/// Buffer returned by the library
+#[repr(C)]
+pub struct ZPNG_Buffer {
+    /// Pointer to data
+    pub Data: *mut c_uchar,
+
+    /// Size of buffer in bytes
+    pub Bytes: c_uint,
+}
+
+/// Image data passed to ZPNG_Compress() or returned by ZPNG_Decompress()
+#[repr(C)]
+pub struct ZPNG_ImageData {
+    /// Pixel data
+    pub Buffer: ZPNG_Buffer,
+
+    /// Number of bytes for each color channel (1, 2 or 4), wider samples are little endian
+    pub BytesPerChannel: c_uint,
+
+    /// Number of channels for each pixel (1-4, or 1-8 for 1 byte channels)
+    pub Channels: c_uint,
+
+    /// Width in pixels of image
+    pub WidthPixels: c_uint,
+
+    /// Height in pixels of image
+    pub HeightPixels: c_uint,
+
+    /// Width of pixel row in bytes, 0 for rows without padding
+    pub StrideBytes: c_uint,
+}
+
+/// Compress image into a buffer.
+///
+/// Writes what the reference Zpng writes, so 16 bit channels are filtered byte by byte.
+///
+/// The returned buffer should be passed to ZPNG_Free().
+///
+/// On success returns a valid data pointer.
+/// On failure returns a null pointer.
+///
+/// # Safety
+///
+/// `imageData` has to be null or point to an image whose `Buffer` holds at least `Bytes` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ZPNG_Compress(imageData: *const ZPNG_ImageData) -> ZPNG_Buffer {
+    // unwinding into C is undefined behaviour
+    match panic::catch_unwind(AssertUnwindSafe(|| Compress(imageData.as_ref()?))) {
+        Ok(Some(data)) => IntoBuffer(data),
+        _ => NullBuffer(),
+    }
+}
+
+/// Decompress image from a buffer.
+///
+/// The returned ZPNG_Buffer should be passed to ZPNG_Free().
+///
+/// On success returns a valid data pointer.
+/// On failure returns a null pointer.
+///
+/// # Safety
+///
+/// `buffer.Data` has to be null or point to at least `buffer.Bytes` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ZPNG_Decompress(buffer: ZPNG_Buffer) -> ZPNG_ImageData {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        crate::ZPNG_Decompress(&crate::ZPNG_Buffer {
+            Data: AsSlice(&buffer)?.to_vec(),
+        })
+        .ok()
+    }));
+
+    let image = match result {
+        Ok(Some(image)) => image,
+        _ => return NullImage(),
+    };
+    let pixelBytes = image.Channels as u32 * image.BytesPerChannel as u32;
+    let strideBytes = match image.WidthPixels.checked_mul(pixelBytes) {
+        Some(strideBytes) if image.Buffer.Data.len() <= c_uint::MAX as usize => strideBytes,
+        _ => return NullImage(),
+    };
+
+    ZPNG_ImageData {
+        BytesPerChannel: image.BytesPerChannel as c_uint,
+        Channels: image.Channels as c_uint,
+        WidthPixels: image.WidthPixels,
+        HeightPixels: image.HeightPixels,
+        StrideBytes: strideBytes,
+        Buffer: IntoBuffer(image.Buffer.Data),
+    }
+}
+
+/// Free buffer when done to avoid leaks.
+///
+/// This will also set the buffer to null.
+///
+/// # Safety
+///
+/// `buffer` has to be null or point to a buffer returned by this library, which was not changed.
+#[no_mangle]
+pub unsafe extern "C" fn ZPNG_Free(buffer: *mut ZPNG_Buffer) {
+    let buffer = match buffer.as_mut() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    if !buffer.Data.is_null() {
+        let data = ptr::slice_from_raw_parts_mut(buffer.Data, buffer.Bytes as usize);
+        drop(Box::from_raw(data));
+    }
+    buffer.Data = ptr::null_mut();
+    buffer.Bytes = 0;
+}
+
+/// Copies the rows of the image out of the caller's buffer and compresses them
+unsafe fn Compress(imageData: &ZPNG_ImageData) -> Option<Vec<u8>> {
+    let channels = u8::try_from(imageData.Channels).ok()?;
+    let bytesPerChannel = u8::try_from(imageData.BytesPerChannel).ok()?;
+    let height = imageData.HeightPixels as usize;
+    let rowBytes = (imageData.WidthPixels as usize)
+        .checked_mul(channels as usize * bytesPerChannel as usize)?;
+    let strideBytes = match imageData.StrideBytes {
+        0 => rowBytes,
+        strideBytes => strideBytes as usize,
+    };
+    if strideBytes < rowBytes {
+        return None;
+    }
+
+    // the last row does not need its padding
+    let input = AsSlice(&imageData.Buffer)?;
+    let neededBytes = match height {
+        0 => 0,
+        _ => strideBytes.checked_mul(height - 1)?.checked_add(rowBytes)?,
+    };
+    if input.len() < neededBytes {
+        return None;
+    }
+
+    let mut pixels = Vec::with_capacity(rowBytes.checked_mul(height)?);
+    for row in 0..height {
+        pixels.extend_from_slice(&input[row * strideBytes..row * strideBytes + rowBytes]);
+    }
+
+    let image = crate::ZPNG_ImageData {
+        Buffer: crate::ZPNG_Buffer { Data: pixels },
+        BytesPerChannel: bytesPerChannel,
+        Channels: channels,
+        WidthPixels: imageData.WidthPixels,
+        HeightPixels: imageData.HeightPixels,
+    };
+    let compressed = crate::ZPNG_Compress(&image).ok()?;
+    if compressed.Data.len() > c_uint::MAX as usize {
+        return None;
+    }
+    Some(compressed.Data)
+}
+
+/// The bytes of a buffer passed in by the caller, `None` for a null pointer
+unsafe fn AsSlice(buffer: &ZPNG_Buffer) -> Option<&[u8]> {
+    match buffer.Bytes {
+        _ if buffer.Data.is_null() => None,
+        // the pointer of an empty buffer may not be aligned or point anywhere
+        0 => Some(&[]),
+        bytes => Some(slice::from_raw_parts(buffer.Data, bytes as usize)),
+    }
+}
+
+/// Hands the data over to the caller, who gives it back to `ZPNG_Free`
+fn IntoBuffer(data: Vec<u8>) -> ZPNG_Buffer {
+    debug_assert!(data.len() <= c_uint::MAX as usize);
+    let bytes = data.len() as c_uint;
+    ZPNG_Buffer {
+        Data: Box::into_raw(data.into_boxed_slice()) as *mut c_uchar,
+        Bytes: bytes,
+    }
+}
+
+fn NullBuffer() -> ZPNG_Buffer {
+    ZPNG_Buffer {
+        Data: ptr::null_mut(),
+        Bytes: 0,
+    }
+}
+
+fn NullImage() -> ZPNG_ImageData {
+    ZPNG_ImageData {
+        Buffer: NullBuffer(),
+        BytesPerChannel: 0,
+        Channels: 0,
+        WidthPixels: 0,
+        HeightPixels: 0,
+        StrideBytes: 0,
+    }
+}
@@ -0,0 +1,202 @@
+//! Decoder and encoder for the `image` crate, so `.zpng` files can be used like any other format
+//!
+//! ```no_run
+//! use image::{DynamicImage, GenericImageView, ImageEncoder};
+//! use zpng_rs_lib::{ZpngDecoder, ZpngEncoder};
+//!
+//! let file = std::fs::File::open("in.zpng").unwrap();
+//! let image = DynamicImage::from_decoder(ZpngDecoder::new(file).unwrap()).unwrap();
+//!
+//! let (width, height) = image.dimensions();
+//! let output = std::fs::File::create("out.zpng").unwrap();
+//! ZpngEncoder::new(output)
+//!     .write_image(image.as_bytes(), width, height, image.color())
+//!     .unwrap();
+//! ```
+
+use crate::{
+    CompressOptions, DecodeLimits, ZPNG_Buffer, ZPNG_CompressWithOptions,
+    ZPNG_DecompressWithLimits, ZPNG_ImageData, ZPNG_ReadHeader, ZpngError,
+};
+use image::error::{
+    DecodingError, EncodingError, ImageFormatHint, LimitError, LimitErrorKind, ParameterError,
+    ParameterErrorKind, UnsupportedError, UnsupportedErrorKind,
+};
+use image::{ColorType, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageResult};
+use std::io::{Cursor, Read, Write};
+
+/// `image::ImageDecoder` for `.zpng` files
+///
+/// The format can't be decoded in pieces, so the whole image is decompressed when the decoder is
+/// created. 8 and 16 bit gray, gray alpha, RGB and RGBA images are supported.
+pub struct ZpngDecoder {
+    Image: ZPNG_ImageData,
+    Color: ColorType,
+}
+
+impl ZpngDecoder {
+    /// Reads and decompresses a whole `.zpng` file
+    pub fn new<R: Read>(reader: R) -> ImageResult<ZpngDecoder> {
+        ZpngDecoder::with_limits(reader, &DecodeLimits::default())
+    }
+
+    /// Like `new`, but refuses images over the limits, use this for files from untrusted sources
+    pub fn with_limits<R: Read>(mut reader: R, limits: &DecodeLimits) -> ImageResult<ZpngDecoder> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        // the layout is in the header, images the image crate can't hold are not decompressed
+        let header = ZPNG_ReadHeader(&data).map_err(DecodingFailed)?;
+        let color = ColorTypeOf(header.Channels, header.BytesPerChannel)?;
+        let image = ZPNG_DecompressWithLimits(&ZPNG_Buffer { Data: data }, limits)
+            .map_err(DecodingFailed)?;
+
+        Ok(ZpngDecoder {
+            Image: image,
+            Color: color,
+        })
+    }
+}
+
+impl<'a> ImageDecoder<'a> for ZpngDecoder {
+    type Reader = Cursor<Vec<u8>>;
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.Image.WidthPixels, self.Image.HeightPixels)
+    }
+
+    fn color_type(&self) -> ColorType {
+        self.Color
+    }
+
+    fn into_reader(self) -> ImageResult<Self::Reader> {
+        let mut data = self.Image.Buffer.Data;
+        SwapLittleNative(&mut data, self.Image.BytesPerChannel);
+        Ok(Cursor::new(data))
+    }
+}
+
+/// `image::ImageEncoder` for `.zpng` files
+///
+/// Takes 8 and 16 bit gray, gray alpha, RGB and RGBA images.
+pub struct ZpngEncoder<W: Write> {
+    Writer: W,
+    Options: CompressOptions,
+}
+
+impl<W: Write> ZpngEncoder<W> {
+    /// Encoder with the default `CompressOptions`
+    pub fn new(writer: W) -> ZpngEncoder<W> {
+        ZpngEncoder::with_options(writer, CompressOptions::default())
+    }
+
+    /// Encoder with the given settings, e.g. a higher level, another predictor or
+    /// `Filter16Bit`, which makes 16 bit images smaller than the defaults the reference Zpng reads
+    pub fn with_options(writer: W, options: CompressOptions) -> ZpngEncoder<W> {
+        ZpngEncoder {
+            Writer: writer,
+            Options: options,
+        }
+    }
+}
+
+impl<W: Write> ImageEncoder for ZpngEncoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ColorType,
+    ) -> ImageResult<()> {
+        let (channels, bytesPerChannel) = LayoutOf(color_type)?;
+        let byteCount = width as u64 * height as u64 * channels as u64 * bytesPerChannel as u64;
+        if buf.len() as u64 != byteCount {
+            return Err(ImageError::Parameter(ParameterError::from_kind(
+                ParameterErrorKind::DimensionMismatch,
+            )));
+        }
+
+        let mut data = buf.to_vec();
+        SwapLittleNative(&mut data, bytesPerChannel);
+        let image = ZPNG_ImageData {
+            Buffer: ZPNG_Buffer { Data: data },
+            BytesPerChannel: bytesPerChannel,
+            Channels: channels,
+            WidthPixels: width,
+            HeightPixels: height,
+        };
+
+        let compressed = ZPNG_CompressWithOptions(&image, &self.Options).map_err(EncodingFailed)?;
+        self.Writer.write_all(&compressed.Data)?;
+        Ok(())
+    }
+}
+
+fn ColorTypeOf(channels: u8, bytesPerChannel: u8) -> ImageResult<ColorType> {
+    Ok(match (channels, bytesPerChannel) {
+        (1, 1) => ColorType::L8,
+        (2, 1) => ColorType::La8,
+        (3, 1) => ColorType::Rgb8,
+        (4, 1) => ColorType::Rgba8,
+        (1, 2) => ColorType::L16,
+        (2, 2) => ColorType::La16,
+        (3, 2) => ColorType::Rgb16,
+        (4, 2) => ColorType::Rgba16,
+        _ => {
+            let bits = (channels as u32 * bytesPerChannel as u32 * 8).min(u8::MAX as u32);
+            return Err(Unsupported(ExtendedColorType::Unknown(bits as u8)));
+        }
+    })
+}
+
+fn LayoutOf(colorType: ColorType) -> ImageResult<(u8, u8)> {
+    Ok(match colorType {
+        ColorType::L8 => (1, 1),
+        ColorType::La8 => (2, 1),
+        ColorType::Rgb8 => (3, 1),
+        ColorType::Rgba8 => (4, 1),
+        ColorType::L16 => (1, 2),
+        ColorType::La16 => (2, 2),
+        ColorType::Rgb16 => (3, 2),
+        ColorType::Rgba16 => (4, 2),
+        // BGR would come back as RGB
+        _ => return Err(Unsupported(colorType.into())),
+    })
+}
+
+/// zpng stores wider samples little endian, image wants them in native byte order
+fn SwapLittleNative(data: &mut [u8], bytesPerChannel: u8) {
+    if cfg!(target_endian = "big") && bytesPerChannel == 2 {
+        for sample in data.chunks_exact_mut(2) {
+            sample.swap(0, 1);
+        }
+    }
+}
+
+fn Format() -> ImageFormatHint {
+    ImageFormatHint::Name("zpng".to_string())
+}
+
+fn Unsupported(color: ExtendedColorType) -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        Format(),
+        UnsupportedErrorKind::Color(color),
+    ))
+}
+
+fn DecodingFailed(err: ZpngError) -> ImageError {
+    match err {
+        ZpngError::LimitExceeded {
+            Limit: "pixel count",
+            ..
+        } => ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError)),
+        ZpngError::LimitExceeded { .. } => {
+            ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory))
+        }
+        err => ImageError::Decoding(DecodingError::new(Format(), err)),
+    }
+}
+
+fn EncodingFailed(err: ZpngError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(Format(), err))
+}
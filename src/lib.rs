@@ -8,6 +8,25 @@
 
 // TODO performance? https://godbolt.org/z/Ezhnh_
 
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "zstd-safe")]
+use zstd_safe::CParameter;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// C API mirroring `zpng.h` of the reference Zpng, built into the cdylib
+pub mod capi;
+
+#[cfg(feature = "image")]
+mod image_codec;
+#[cfg(feature = "image")]
+pub use image_codec::{ZpngDecoder, ZpngEncoder};
+
 /// kept this for typesafety
 /// Image data returned by the library
 #[derive(Debug)]
@@ -21,137 +40,727 @@ pub struct ZPNG_ImageData {
     /// Pixel data
     pub Buffer: ZPNG_Buffer,
 
-    /// Number of bytes for each color channel (1-2)
+    /// Number of bytes for each color channel (1, 2 or 4), wider samples are little endian
     pub BytesPerChannel: u8,
 
-    /// Number of channels for each pixel (1-4)
+    /// Number of channels for each pixel (1-4, or 1-8 for 1 byte channels)
     pub Channels: u8,
 
     /// Width in pixels of image
-    pub WidthPixels: u16,
+    pub WidthPixels: u32,
 
     /// Height in pixels of image
-    pub HeightPixels: u16,
+    pub HeightPixels: u32,
+}
+
+/// Reasons why compressing or decompressing an image failed
+#[derive(Debug)]
+pub enum ZpngError {
+    /// The buffer does not start with `ZPNG_HEADER_MAGIC` or `ZPNG_HEADER_MAGIC_V2`, contains the
+    /// magic that was found
+    BadMagic(u16),
+
+    /// The buffer is shorter than the header, contains the length of the buffer
+    TruncatedHeader(usize),
+
+    /// The combination of channels and bytes per channel can not be handled
+    UnsupportedFormat { Channels: u8, BytesPerChannel: u8 },
+
+    /// The size given by the header does not match the size of the pixel data
+    SizeMismatch { Expected: u64, Actual: u64 },
+
+    /// zstd failed to compress or decompress the pixel data
+    Zstd(std::io::Error),
+
+    /// A header field has a value this version does not know about
+    UnknownHeaderValue { Field: &'static str, Value: u32 },
+
+    /// The header asks for more than the `DecodeLimits` allow
+    LimitExceeded {
+        Limit: &'static str,
+        Value: u64,
+        Max: u64,
+    },
+
+    /// No custom filter with this ID has been registered with `ZPNG_RegisterFilter`
+    UnknownFilter(u16),
+
+    /// `ZPNG_RegisterFilter` was called twice with the same ID
+    FilterAlreadyRegistered(u16),
+
+    /// One of the entropy coders besides zstd failed to compress or decompress the pixel data
+    Backend(EntropyBackend, std::io::Error),
+
+    /// The entropy backend was left out of this build, see the cargo features
+    BackendDisabled(EntropyBackend),
+}
+
+impl fmt::Display for ZpngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZpngError::BadMagic(magic) => write!(
+                f,
+                "bad magic {:#06x}, expected {:#06x} or {:#06x}",
+                magic, ZPNG_HEADER_MAGIC, ZPNG_HEADER_MAGIC_V2
+            ),
+            ZpngError::TruncatedHeader(len) => {
+                write!(f, "truncated header, got only {} bytes", len)
+            }
+            ZpngError::UnsupportedFormat {
+                Channels,
+                BytesPerChannel,
+            } => write!(
+                f,
+                "unsupported format with {} channels and {} bytes per channel",
+                Channels, BytesPerChannel
+            ),
+            ZpngError::SizeMismatch { Expected, Actual } => write!(
+                f,
+                "size mismatch, expected {} bytes of pixel data but got {}",
+                Expected, Actual
+            ),
+            ZpngError::Zstd(err) => write!(f, "zstd error: {}", err),
+            ZpngError::UnknownHeaderValue { Field, Value } => {
+                write!(f, "unknown value {} in header field {}", Value, Field)
+            }
+            ZpngError::LimitExceeded { Limit, Value, Max } => write!(
+                f,
+                "image exceeds the {} limit, {} is more than {}",
+                Limit, Value, Max
+            ),
+            ZpngError::UnknownFilter(id) => write!(f, "no filter registered with id {}", id),
+            ZpngError::FilterAlreadyRegistered(id) => {
+                write!(f, "a filter with id {} is already registered", id)
+            }
+            ZpngError::Backend(backend, err) => write!(f, "{:?} error: {}", backend, err),
+            ZpngError::BackendDisabled(backend) => {
+                write!(f, "built without the {:?} entropy backend", backend)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ZpngError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZpngError::Zstd(err) => Some(err),
+            ZpngError::Backend(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for `ZPNG_CompressWithOptions`
+#[derive(Debug, Clone, Copy)]
+pub struct CompressOptions {
+    /// Color transform for RGB(A) images, ignored for layouts it does not apply to
+    ///
+    /// Defaults to `ZPNG_ColorFilter::GbRg` with the `ENABLE_RGB_COLOR_FILTER` feature, like the
+    /// reference Zpng. Whatever is picked is recorded in the file, so any build can decode it.
+    pub ColorFilter: ZPNG_ColorFilter,
+
+    /// Spatial predictor, the reference Zpng only knows `ZPNG_Predictor::Left`
+    ///
+    /// Ignored for 32 bit samples, which are always XORed with their left neighbor.
+    pub Predictor: ZPNG_Predictor,
+
+    /// ID of a filter registered with `ZPNG_RegisterFilter` to use instead of the built in ones
+    ///
+    /// The color filter, predictor and sample filtering settings are ignored when this is set.
+    pub CustomFilter: Option<u16>,
+
+    /// Filter images with 2 bytes per channel as 16 bit samples instead of byte by byte
+    ///
+    /// This predicts much better but needs the v2 header, which the reference Zpng can not read,
+    /// so it is off by default. Picking `ZPNG_ColorFilter::YCoCgR` for RGB(A) turns it on.
+    pub Filter16Bit: bool,
+
+    /// Filter images with 4 bytes per channel as 32 bit samples instead of byte by byte
+    ///
+    /// Each sample is XORed with the one to its left, which keeps the shared sign and exponent
+    /// bits of floats out of the way. This needs the v2 header as well and is off by default.
+    pub Filter32Bit: bool,
+
+    /// Entropy coder for the filtered data, only zstd can be read by the reference Zpng
+    pub EntropyBackend: EntropyBackend,
+
+    /// zstd compression level, from the negative fast levels up to 22 for the smallest output
+    ///
+    /// Deflate clamps it to 0-9 and brotli to 0-11, lz4 has no levels.
+    pub Level: i32,
+
+    /// log2 of the zstd window size, 0 lets zstd pick one for the level
+    pub WindowLog: u32,
+
+    /// Enable zstd long distance matching, helps with repetitions far apart in large images
+    pub LongDistanceMatching: bool,
+
+    /// Number of zstd worker threads, 0 uses one per core
+    ///
+    /// zstd always runs in its multithreaded mode, which splits the input into jobs whose size
+    /// only depends on the other settings, so the output is the same for any number of threads.
+    pub Threads: u32,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            ColorFilter: if cfg!(feature = "ENABLE_RGB_COLOR_FILTER") {
+                ZPNG_ColorFilter::GbRg
+            } else {
+                ZPNG_ColorFilter::None
+            },
+            Predictor: ZPNG_Predictor::Left,
+            CustomFilter: None,
+            Filter16Bit: false,
+            Filter32Bit: false,
+            EntropyBackend: EntropyBackend::Zstd,
+            Level: kCompressionLevel,
+            WindowLog: 0,
+            LongDistanceMatching: false,
+            Threads: 0,
+        }
+    }
+}
+
+/// A reversible transform of the pixels, replacing the built in filters before the entropy coder
+///
+/// Implementations are made known with `ZPNG_RegisterFilter` and picked with
+/// `CompressOptions::CustomFilter`. The ID ends up in the file, so a filter has to be registered
+/// under the same ID before such a file can be decompressed.
+pub trait Filter: Send + Sync {
+    /// ID written to the header, unique among the registered filters
+    fn Id(&self) -> u16;
+
+    /// Transforms the pixels of `image` into the bytes handed to the entropy coder
+    fn Forward(&self, image: &ZPNG_ImageData) -> Vec<u8>;
+
+    /// Restores the pixels of the image described by `header` from the output of `Forward`
+    ///
+    /// `packed` comes straight from the file, so it has to be validated rather than trusted.
+    /// The result must have exactly `header.DecodedBytes()` bytes.
+    fn Inverse(&self, header: &ZPNG_Header, packed: &[u8]) -> Result<Vec<u8>, ZpngError>;
+}
+
+lazy_static! {
+    static ref FILTERS: RwLock<HashMap<u16, Arc<dyn Filter>>> = RwLock::new(HashMap::new());
+}
+
+/// Make a custom filter available to the encoder and decoder under its `Filter::Id`
+pub fn ZPNG_RegisterFilter(filter: Box<dyn Filter>) -> Result<(), ZpngError> {
+    let mut filters = FILTERS.write().unwrap();
+    let id = filter.Id();
+    if filters.contains_key(&id) {
+        return Err(ZpngError::FilterAlreadyRegistered(id));
+    }
+    filters.insert(id, Arc::from(filter));
+    Ok(())
+}
+
+fn RegisteredFilter(id: u16) -> Result<Arc<dyn Filter>, ZpngError> {
+    match FILTERS.read().unwrap().get(&id) {
+        Some(filter) => Ok(filter.clone()),
+        None => Err(ZpngError::UnknownFilter(id)),
+    }
 }
 
 /// Compress image into a buffer.
-pub fn ZPNG_Compress(imageData: &ZPNG_ImageData) -> Option<ZPNG_Buffer> {
-    let pixelCount: u32 = imageData.WidthPixels as u32 * imageData.HeightPixels as u32;
+pub fn ZPNG_Compress(imageData: &ZPNG_ImageData) -> Result<ZPNG_Buffer, ZpngError> {
+    ZPNG_CompressWithOptions(imageData, &CompressOptions::default())
+}
+
+/// Compress image into a buffer using the given zstd settings
+pub fn ZPNG_CompressWithOptions(
+    imageData: &ZPNG_ImageData,
+    options: &CompressOptions,
+) -> Result<ZPNG_Buffer, ZpngError> {
     let pixelBytes: u32 = imageData.BytesPerChannel as u32 * imageData.Channels as u32;
-    let byteCount = pixelBytes as usize * pixelCount as usize;
+
+    let customFilter = match options.CustomFilter {
+        Some(id) => Some(RegisteredFilter(id)?),
+        None => None,
+    };
+
+    // YCoCg-R has no byte by byte variant for 16 bit channels, picking it filters whole samples
+    let ycocg16 = options.ColorFilter == ZPNG_ColorFilter::YCoCgR
+        && (imageData.Channels == 3 || imageData.Channels == 4);
+    let flags = match imageData.BytesPerChannel {
+        _ if customFilter.is_some() => ZPNG_FLAG_CUSTOM_FILTER,
+        2 if options.Filter16Bit || ycocg16 => ZPNG_FLAG_16BIT_SAMPLES,
+        4 if options.Filter32Bit => ZPNG_FLAG_32BIT_SAMPLES,
+        _ => 0,
+    };
+
+    let colorFilter = if customFilter.is_none()
+        && ColorFilterApplies(
+            options.ColorFilter,
+            imageData.Channels,
+            imageData.BytesPerChannel,
+            flags,
+        ) {
+        options.ColorFilter
+    } else {
+        ZPNG_ColorFilter::None
+    };
+
+    let predictor = if flags & (ZPNG_FLAG_32BIT_SAMPLES | ZPNG_FLAG_CUSTOM_FILTER) != 0 {
+        ZPNG_Predictor::Left
+    } else {
+        options.Predictor
+    };
+
+    // the original header only has room for 16 bit dimensions and no flags, only use the wider
+    // one if needed so that the output stays readable by the reference implementation
+    let fitsV1 = imageData.WidthPixels <= u16::MAX as u32
+        && imageData.HeightPixels <= u16::MAX as u32
+        && flags == 0
+        && colorFilter == ColorFilterV1(imageData.Channels, imageData.BytesPerChannel)
+        && predictor == ZPNG_Predictor::Left
+        && options.EntropyBackend == EntropyBackend::Zstd;
+    let header = ZPNG_Header {
+        Magic: if fitsV1 {
+            ZPNG_HEADER_MAGIC
+        } else {
+            ZPNG_HEADER_MAGIC_V2
+        },
+        Width: imageData.WidthPixels,
+        Height: imageData.HeightPixels,
+        Channels: imageData.Channels,
+        BytesPerChannel: imageData.BytesPerChannel,
+        Flags: flags,
+        ColorFilter: colorFilter,
+        Predictor: predictor,
+        CustomFilter: options.CustomFilter,
+        EntropyBackend: options.EntropyBackend,
+    };
 
     // FIXME: One day add support for other formats
-    if pixelBytes > 8 {
-        return None;
+    if !IsSupportedFormat(imageData.Channels, imageData.BytesPerChannel, flags) {
+        return Err(ZpngError::UnsupportedFormat {
+            Channels: imageData.Channels,
+            BytesPerChannel: imageData.BytesPerChannel,
+        });
     };
 
+    // the filters index the input by width and height, make sure it is large enough
+    if imageData.Buffer.Data.len() as u64 != header.DecodedBytes() {
+        return Err(ZpngError::SizeMismatch {
+            Expected: header.DecodedBytes(),
+            Actual: imageData.Buffer.Data.len() as u64,
+        });
+    }
+    let byteCount = imageData.Buffer.Data.len();
+
     // Pass 1: Pack and filter data.
     let width = imageData.WidthPixels;
     let height = imageData.HeightPixels;
-    let packing = match pixelBytes {
-        1 => PackAndFilter::<1>(&imageData.Buffer.Data, width, height, byteCount),
-        2 => PackAndFilter::<2>(&imageData.Buffer.Data, width, height, byteCount),
-        3 => {
-            if cfg!(feature = "ENABLE_RGB_COLOR_FILTER") {
-                PackAndFilter_3(&imageData.Buffer.Data, width, height, byteCount)
-            } else {
-                PackAndFilter::<3>(&imageData.Buffer.Data, width, height, byteCount)
-            }
+    let gbrg = header.ColorFilter == ZPNG_ColorFilter::GbRg;
+    let predictors = if header.Predictor == ZPNG_Predictor::Adaptive {
+        // the wide filters predict whole samples, the others single bytes
+        let (samples, sampleBytes) = if header.ColorFilter == ZPNG_ColorFilter::YCoCgR
+            || header.Flags & ZPNG_FLAG_16BIT_SAMPLES != 0
+        {
+            (
+                imageData.Channels as usize,
+                imageData.BytesPerChannel as usize,
+            )
+        } else {
+            (pixelBytes as usize, 1)
+        };
+        ChoosePredictors(&imageData.Buffer.Data, width, height, samples, sampleBytes)
+    } else {
+        vec![header.Predictor; PredictorBands(height)]
+    };
+    let predictor = &predictors[..];
+    let mut packing = if let Some(filter) = &customFilter {
+        filter.Forward(imageData)
+    } else if header.ColorFilter == ZPNG_ColorFilter::YCoCgR {
+        let input = &imageData.Buffer.Data;
+        match (imageData.Channels, imageData.BytesPerChannel) {
+            (3, 1) => PackAndFilterYCoCg::<3, 1>(input, width, height, predictor),
+            (4, 1) => PackAndFilterYCoCg::<4, 1>(input, width, height, predictor),
+            (3, 2) => PackAndFilterYCoCg::<3, 2>(input, width, height, predictor),
+            (4, 2) => PackAndFilterYCoCg::<4, 2>(input, width, height, predictor),
+            _ => unreachable!("checked by ColorFilterApplies"),
         }
-        4 => {
-            if cfg!(feature = "ENABLE_RGB_COLOR_FILTER") {
-                PackAndFilter_4(&imageData.Buffer.Data, width, height, byteCount)
-            } else {
-                PackAndFilter::<4>(&imageData.Buffer.Data, width, height, byteCount)
+    } else if header.Flags & ZPNG_FLAG_16BIT_SAMPLES != 0 {
+        let input = &imageData.Buffer.Data;
+        match imageData.Channels {
+            1 => PackAndFilter16::<1>(input, width, height, byteCount, gbrg, predictor),
+            2 => PackAndFilter16::<2>(input, width, height, byteCount, gbrg, predictor),
+            3 => PackAndFilter16::<3>(input, width, height, byteCount, gbrg, predictor),
+            4 => PackAndFilter16::<4>(input, width, height, byteCount, gbrg, predictor),
+            _ => unreachable!("checked by IsSupportedFormat"),
+        }
+    } else if header.Flags & ZPNG_FLAG_32BIT_SAMPLES != 0 {
+        let input = &imageData.Buffer.Data;
+        match imageData.Channels {
+            1 => PackAndFilter32::<1>(input, width, height, byteCount),
+            2 => PackAndFilter32::<2>(input, width, height, byteCount),
+            3 => PackAndFilter32::<3>(input, width, height, byteCount),
+            4 => PackAndFilter32::<4>(input, width, height, byteCount),
+            _ => unreachable!("checked by IsSupportedFormat"),
+        }
+    } else {
+        match pixelBytes {
+            1 => PackAndFilter::<1>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            2 => PackAndFilter::<2>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            3 => {
+                if gbrg {
+                    PackAndFilter_3(&imageData.Buffer.Data, width, height, byteCount, predictor)
+                } else {
+                    PackAndFilter::<3>(&imageData.Buffer.Data, width, height, byteCount, predictor)
+                }
+            }
+            4 => {
+                if gbrg {
+                    PackAndFilter_4(&imageData.Buffer.Data, width, height, byteCount, predictor)
+                } else {
+                    PackAndFilter::<4>(&imageData.Buffer.Data, width, height, byteCount, predictor)
+                }
+            }
+            5 => PackAndFilter::<5>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            6 => PackAndFilter::<6>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            7 => PackAndFilter::<7>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            8 => PackAndFilter::<8>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            12 => PackAndFilter::<12>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            16 => PackAndFilter::<16>(&imageData.Buffer.Data, width, height, byteCount, predictor),
+            _ => {
+                return Err(ZpngError::UnsupportedFormat {
+                    Channels: imageData.Channels,
+                    BytesPerChannel: imageData.BytesPerChannel,
+                })
             }
         }
-        5 => PackAndFilter::<5>(&imageData.Buffer.Data, width, height, byteCount),
-        6 => PackAndFilter::<6>(&imageData.Buffer.Data, width, height, byteCount),
-        7 => PackAndFilter::<7>(&imageData.Buffer.Data, width, height, byteCount),
-        8 => PackAndFilter::<8>(&imageData.Buffer.Data, width, height, byteCount),
-        _ => unreachable!(),
     };
 
-    // Pass 2: Compress the packed/filtered data.
-    let mut output = if cfg!(not(feature = "WASM")) {
-        zstd::block::compress(&packing, kCompressionLevel).ok()?
+    if header.Predictor == ZPNG_Predictor::Adaptive {
+        packing.extend(predictors.iter().map(|&predictor| predictor as u8));
+    }
+
+    // Pass 2: Compress the packed/filtered data, by default into a regular zstd frame like the
+    // reference Zpng.
+    let mut output = EntropyCompress(&packing, options)?;
+
+    // Write header
+    let mut data = WriteHeader(&header);
+    data.append(&mut output);
+
+    Ok(ZPNG_Buffer { Data: data })
+}
+
+/// Limits checked against the header before anything is allocated for decompression
+///
+/// A header of a few bytes can claim an image of many gigabytes, use these when decoding files
+/// from untrusted sources. The default only caps the compression ratio at 65536, which no zstd,
+/// lz4 or deflate payload can reach. Brotli can, so brotli payloads are not held to the ratio,
+/// their buffer grows with the data they really decode instead of the size in the header.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    /// Maximum number of pixels (width * height)
+    pub MaxPixels: u64,
+
+    /// Maximum number of bytes allocated while decoding
+    pub MaxAlloc: u64,
+
+    /// Maximum ratio of decoded bytes to compressed bytes, `u64::MAX` turns the check off
+    ///
+    /// Not applied to brotli payloads.
+    pub MaxRatio: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            MaxPixels: u64::MAX,
+            MaxAlloc: u64::MAX,
+            MaxRatio: kDefaultMaxRatio,
+        }
+    }
+}
+
+/// Read and validate the header at the start of a compressed image
+///
+/// Only the first `ZPNG_HEADER_OVERHEAD_BYTES` or `ZPNG_HEADER_V2_OVERHEAD_BYTES` bytes
+/// (depending on the magic) are looked at, so this is cheap enough for listing files or sizing
+/// buffers before deciding to decompress.
+pub fn ZPNG_ReadHeader(buffer: &[u8]) -> Result<ZPNG_Header, ZpngError> {
+    if buffer.len() < 2 {
+        return Err(ZpngError::TruncatedHeader(buffer.len()));
+    }
+
+    let magic = u16::from_le_bytes([buffer[0], buffer[1]]);
+    let overhead = match magic {
+        ZPNG_HEADER_MAGIC => ZPNG_HEADER_OVERHEAD_BYTES,
+        ZPNG_HEADER_MAGIC_V2 => ZPNG_HEADER_V2_OVERHEAD_BYTES,
+        _ => return Err(ZpngError::BadMagic(magic)),
+    };
+    if buffer.len() < overhead as usize {
+        return Err(ZpngError::TruncatedHeader(buffer.len()));
+    }
+
+    let header = if magic == ZPNG_HEADER_MAGIC {
+        ZPNG_Header {
+            Magic: magic,
+            Width: u16::from_le_bytes([buffer[2], buffer[3]]) as u32,
+            Height: u16::from_le_bytes([buffer[4], buffer[5]]) as u32,
+            Channels: buffer[6],
+            BytesPerChannel: buffer[7],
+            Flags: 0,
+            ColorFilter: ColorFilterV1(buffer[6], buffer[7]),
+            Predictor: ZPNG_Predictor::Left,
+            CustomFilter: None,
+            EntropyBackend: EntropyBackend::Zstd,
+        }
     } else {
-        todo!()
+        let mut header = ZPNG_Header {
+            Magic: magic,
+            Channels: buffer[2],
+            BytesPerChannel: buffer[3],
+            Width: u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]),
+            Height: u32::from_le_bytes([buffer[8], buffer[9], buffer[10], buffer[11]]),
+            Flags: buffer[12],
+            ColorFilter: ZPNG_ColorFilter::None,
+            Predictor: ZPNG_Predictor::Left,
+            CustomFilter: None,
+            EntropyBackend: match buffer[15] {
+                0 => EntropyBackend::Zstd,
+                1 => EntropyBackend::Lz4,
+                2 => EntropyBackend::Deflate,
+                3 => EntropyBackend::Brotli,
+                value => {
+                    return Err(ZpngError::UnknownHeaderValue {
+                        Field: "entropy backend",
+                        Value: value as u32,
+                    })
+                }
+            },
+        };
+
+        // a custom filter replaces the built in ones, so its ID takes their place
+        if header.Flags & ZPNG_FLAG_CUSTOM_FILTER != 0 {
+            header.CustomFilter = Some(u16::from_le_bytes([buffer[13], buffer[14]]));
+        } else {
+            header.ColorFilter = match buffer[13] {
+                0 => ZPNG_ColorFilter::None,
+                1 => ZPNG_ColorFilter::GbRg,
+                2 => ZPNG_ColorFilter::YCoCgR,
+                value => {
+                    return Err(ZpngError::UnknownHeaderValue {
+                        Field: "color filter",
+                        Value: value as u32,
+                    })
+                }
+            };
+            header.Predictor = match PredictorFromByte(buffer[14]) {
+                Some(predictor) => predictor,
+                None => {
+                    return Err(ZpngError::UnknownHeaderValue {
+                        Field: "predictor",
+                        Value: buffer[14] as u32,
+                    })
+                }
+            };
+        }
+        header
     };
 
-    // Write header
-    let mut header = Vec::<u8>::with_capacity(ZPNG_HEADER_OVERHEAD_BYTES as usize);
+    // sample filtering only makes sense for channels of the matching width, a custom filter
+    // takes care of everything by itself
+    let knownFlags = match header.BytesPerChannel {
+        _ if header.Flags & ZPNG_FLAG_CUSTOM_FILTER != 0 => ZPNG_FLAG_CUSTOM_FILTER,
+        2 => ZPNG_FLAG_16BIT_SAMPLES,
+        4 => ZPNG_FLAG_32BIT_SAMPLES,
+        _ => 0,
+    };
+    if header.Flags & !knownFlags != 0 {
+        return Err(ZpngError::UnknownHeaderValue {
+            Field: "flags",
+            Value: header.Flags as u32,
+        });
+    }
+
+    if !IsSupportedFormat(header.Channels, header.BytesPerChannel, header.Flags) {
+        return Err(ZpngError::UnsupportedFormat {
+            Channels: header.Channels,
+            BytesPerChannel: header.BytesPerChannel,
+        });
+    }
 
-    header.extend_from_slice(&u16::to_le_bytes(ZPNG_HEADER_MAGIC));
-    header.extend_from_slice(&u16::to_le_bytes(imageData.WidthPixels));
-    header.extend_from_slice(&u16::to_le_bytes(imageData.HeightPixels));
-    header.extend_from_slice(&u8::to_le_bytes(imageData.Channels));
-    header.extend_from_slice(&u8::to_le_bytes(imageData.BytesPerChannel));
+    if !ColorFilterApplies(
+        header.ColorFilter,
+        header.Channels,
+        header.BytesPerChannel,
+        header.Flags,
+    ) {
+        return Err(ZpngError::UnknownHeaderValue {
+            Field: "color filter",
+            Value: header.ColorFilter as u32,
+        });
+    }
 
-    debug_assert_eq!(&header.len(), &(ZPNG_HEADER_OVERHEAD_BYTES as usize));
-    header.append(&mut output);
+    if header.Flags & ZPNG_FLAG_32BIT_SAMPLES != 0 && header.Predictor != ZPNG_Predictor::Left {
+        return Err(ZpngError::UnknownHeaderValue {
+            Field: "predictor",
+            Value: header.Predictor as u32,
+        });
+    }
 
-    Some(ZPNG_Buffer { Data: header })
+    Ok(header)
 }
 
 /// Decompress image from a buffer
-pub fn ZPNG_Decompress(buffer: &ZPNG_Buffer) -> Option<ZPNG_ImageData> {
-    let mut buffer = buffer.Data.as_slice();
-
-    debug_assert!(buffer.len() >= ZPNG_HEADER_OVERHEAD_BYTES as usize);
-    debug_assert_eq!(
-        u16::from_le_bytes([buffer[0], buffer[1]]),
-        ZPNG_HEADER_MAGIC
-    );
+pub fn ZPNG_Decompress(buffer: &ZPNG_Buffer) -> Result<ZPNG_ImageData, ZpngError> {
+    ZPNG_DecompressWithLimits(buffer, &DecodeLimits::default())
+}
 
-    // parse the header
-    let width = u16::from_le_bytes([buffer[2], buffer[3]]);
-    let height = u16::from_le_bytes([buffer[4], buffer[5]]);
-    let channels = buffer[6];
-    let bytesPerChannel = buffer[7];
+/// Decompress image from a buffer, rejecting it from the header alone if it exceeds `limits`
+pub fn ZPNG_DecompressWithLimits(
+    buffer: &ZPNG_Buffer,
+    limits: &DecodeLimits,
+) -> Result<ZPNG_ImageData, ZpngError> {
+    let header = ZPNG_ReadHeader(&buffer.Data)?;
 
     // skip the header
-    buffer = &buffer[ZPNG_HEADER_OVERHEAD_BYTES as usize..];
+    let buffer = &buffer.Data[header.OverheadBytes()..];
 
-    let pixelCount = width as u32 * height as u32;
+    let width = header.Width;
+    let height = header.Height;
+    let channels = header.Channels;
+    let bytesPerChannel = header.BytesPerChannel;
     let pixelBytes = bytesPerChannel as u32 * channels as u32;
-    let byteCount = pixelBytes as usize * pixelCount as usize;
 
-    // Stage 1: Decompress back to packing buffer
-    let mut packing = vec![0; byteCount];
-    if cfg!(not(feature = "WASM")) {
-        let size = zstd::block::decompress_to_buffer(&buffer, &mut packing).ok()?;
-        debug_assert_eq!(size, byteCount);
+    let customFilter = match header.CustomFilter {
+        Some(id) => Some(RegisteredFilter(id)?),
+        None => None,
+    };
+
+    let rawBytes = header.DecodedBytes();
+    let packedBytes = if customFilter.is_some() {
+        ContentSize(header.EntropyBackend, buffer)?
     } else {
-        todo!()
+        header.PackedBytes()
     };
+    let allocBytes = packedBytes.saturating_add(rawBytes);
+    let compressedBytes = buffer.len() as u64;
+
+    // the payload has to agree with the header before anything is allocated for it
+    CheckPayload(header.EntropyBackend, buffer, packedBytes)?;
+
+    CheckLimit("pixel count", header.PixelCount(), limits.MaxPixels)?;
+    CheckLimit("allocation", allocBytes, limits.MaxAlloc)?;
+    // brotli reaches any ratio on uniform images, its buffer grows with what it really decodes
+    // instead of being allocated from the header
+    let largestBytes = rawBytes.max(packedBytes);
+    if limits.MaxRatio != u64::MAX
+        && header.EntropyBackend != EntropyBackend::Brotli
+        && largestBytes > compressedBytes.saturating_mul(limits.MaxRatio)
+    {
+        return Err(ZpngError::LimitExceeded {
+            Limit: "compression ratio",
+            Value: largestBytes / compressedBytes.max(1),
+            Max: limits.MaxRatio,
+        });
+    }
+    // the image may not even be addressable on 32 bit platforms
+    CheckLimit("allocation", allocBytes, usize::MAX as u64)?;
+    let byteCount = rawBytes as usize;
+    let packedCount = packedBytes as usize;
+
+    // Stage 1: Decompress back to packing buffer
+    let packing = EntropyDecompress(header.EntropyBackend, buffer, packedCount)?;
 
     // Stage 2: Unpack/Unfilter
-    let zpngBuffer = match pixelBytes {
-        1 => UnpackAndUnfilter::<1>(&packing, width, height, byteCount),
-        2 => UnpackAndUnfilter::<2>(&packing, width, height, byteCount),
-        3 => {
-            if cfg!(feature = "ENABLE_RGB_COLOR_FILTER") {
-                UnpackAndUnfilter_3(&packing, width, height, byteCount)
-            } else {
-                UnpackAndUnfilter::<3>(&packing, width, height, byteCount)
+    let (packing, predictors) = if header.Predictor == ZPNG_Predictor::Adaptive {
+        let (packing, table) = packing.split_at(packedCount - PredictorBands(height));
+        let mut predictors = Vec::with_capacity(table.len());
+        for &value in table {
+            match PredictorFromByte(value) {
+                Some(predictor) if predictor != ZPNG_Predictor::Adaptive => {
+                    predictors.push(predictor)
+                }
+                _ => {
+                    return Err(ZpngError::UnknownHeaderValue {
+                        Field: "predictor table",
+                        Value: value as u32,
+                    })
+                }
             }
         }
-        4 => {
-            if cfg!(feature = "ENABLE_RGB_COLOR_FILTER") {
-                UnpackAndUnfilter_4(&packing, width, height, byteCount)
-            } else {
-                UnpackAndUnfilter::<4>(&packing, width, height, byteCount)
+        (packing, predictors)
+    } else {
+        (&packing[..], vec![header.Predictor; PredictorBands(height)])
+    };
+    let predictor = &predictors[..];
+
+    let gbrg = header.ColorFilter == ZPNG_ColorFilter::GbRg;
+    let zpngBuffer = if let Some(filter) = &customFilter {
+        let data = filter.Inverse(&header, packing)?;
+        if data.len() != byteCount {
+            return Err(ZpngError::SizeMismatch {
+                Expected: rawBytes,
+                Actual: data.len() as u64,
+            });
+        }
+        ZPNG_Buffer { Data: data }
+    } else if header.ColorFilter == ZPNG_ColorFilter::YCoCgR {
+        match (channels, bytesPerChannel) {
+            (3, 1) => UnpackAndUnfilterYCoCg::<3, 1>(packing, width, height, byteCount, predictor),
+            (4, 1) => UnpackAndUnfilterYCoCg::<4, 1>(packing, width, height, byteCount, predictor),
+            (3, 2) => UnpackAndUnfilterYCoCg::<3, 2>(packing, width, height, byteCount, predictor),
+            (4, 2) => UnpackAndUnfilterYCoCg::<4, 2>(packing, width, height, byteCount, predictor),
+            _ => unreachable!("checked by ColorFilterApplies"),
+        }
+    } else if header.Flags & ZPNG_FLAG_16BIT_SAMPLES != 0 {
+        match channels {
+            1 => UnpackAndUnfilter16::<1>(packing, width, height, byteCount, gbrg, predictor),
+            2 => UnpackAndUnfilter16::<2>(packing, width, height, byteCount, gbrg, predictor),
+            3 => UnpackAndUnfilter16::<3>(packing, width, height, byteCount, gbrg, predictor),
+            4 => UnpackAndUnfilter16::<4>(packing, width, height, byteCount, gbrg, predictor),
+            _ => unreachable!("checked by IsSupportedFormat"),
+        }
+    } else if header.Flags & ZPNG_FLAG_32BIT_SAMPLES != 0 {
+        match channels {
+            1 => UnpackAndUnfilter32::<1>(packing, width, height, byteCount),
+            2 => UnpackAndUnfilter32::<2>(packing, width, height, byteCount),
+            3 => UnpackAndUnfilter32::<3>(packing, width, height, byteCount),
+            4 => UnpackAndUnfilter32::<4>(packing, width, height, byteCount),
+            _ => unreachable!("checked by IsSupportedFormat"),
+        }
+    } else {
+        match pixelBytes {
+            1 => UnpackAndUnfilter::<1>(packing, width, height, byteCount, predictor),
+            2 => UnpackAndUnfilter::<2>(packing, width, height, byteCount, predictor),
+            3 => {
+                if gbrg {
+                    UnpackAndUnfilter_3(packing, width, height, byteCount, predictor)
+                } else {
+                    UnpackAndUnfilter::<3>(packing, width, height, byteCount, predictor)
+                }
+            }
+            4 => {
+                if gbrg {
+                    UnpackAndUnfilter_4(packing, width, height, byteCount, predictor)
+                } else {
+                    UnpackAndUnfilter::<4>(packing, width, height, byteCount, predictor)
+                }
+            }
+            5 => UnpackAndUnfilter::<5>(packing, width, height, byteCount, predictor),
+            6 => UnpackAndUnfilter::<6>(packing, width, height, byteCount, predictor),
+            7 => UnpackAndUnfilter::<7>(packing, width, height, byteCount, predictor),
+            8 => UnpackAndUnfilter::<8>(packing, width, height, byteCount, predictor),
+            12 => UnpackAndUnfilter::<12>(packing, width, height, byteCount, predictor),
+            16 => UnpackAndUnfilter::<16>(packing, width, height, byteCount, predictor),
+            _ => {
+                return Err(ZpngError::UnsupportedFormat {
+                    Channels: channels,
+                    BytesPerChannel: bytesPerChannel,
+                })
             }
         }
-        5 => UnpackAndUnfilter::<5>(&packing, width, height, byteCount),
-        6 => UnpackAndUnfilter::<6>(&packing, width, height, byteCount),
-        7 => UnpackAndUnfilter::<7>(&packing, width, height, byteCount),
-        8 => UnpackAndUnfilter::<8>(&packing, width, height, byteCount),
-        _ => unreachable!(),
     };
 
-    Some(ZPNG_ImageData {
+    Ok(ZPNG_ImageData {
         Buffer: zpngBuffer,
         WidthPixels: width,
         HeightPixels: height,
@@ -160,292 +769,1530 @@ pub fn ZPNG_Decompress(buffer: &ZPNG_Buffer) -> Option<ZPNG_ImageData> {
     })
 }
 
-const kCompressionLevel: i32 = 1;
-const ZPNG_HEADER_MAGIC: u16 = 0xFBF8;
-const ZPNG_HEADER_OVERHEAD_BYTES: u8 = std::mem::size_of::<ZPNG_Header>() as u8;
+// the arguments go unused in builds without any of the coders
+#[allow(unused_variables)]
+fn EntropyCompress(packing: &[u8], options: &CompressOptions) -> Result<Vec<u8>, ZpngError> {
+    // the coders besides zstd get the size in front of their stream
+    let backend = options.EntropyBackend;
+    match backend {
+        #[cfg(feature = "zstd-safe")]
+        EntropyBackend::Zstd => ZstdCompress(packing, options),
+        #[cfg(feature = "lz4")]
+        EntropyBackend::Lz4 => {
+            let mut output = u64::to_le_bytes(packing.len() as u64).to_vec();
+            output.extend_from_slice(&lz4_flex::block::compress(packing));
+            Ok(output)
+        }
+        #[cfg(feature = "deflate")]
+        EntropyBackend::Deflate => {
+            use std::io::Write;
+            let output = u64::to_le_bytes(packing.len() as u64).to_vec();
+            let level = flate2::Compression::new((options.Level.max(0) as u32).min(9));
+            let mut encoder = flate2::write::DeflateEncoder::new(output, level);
+            encoder
+                .write_all(packing)
+                .and_then(|_| encoder.finish())
+                .map_err(|err| ZpngError::Backend(backend, err))
+        }
+        #[cfg(feature = "brotli")]
+        EntropyBackend::Brotli => {
+            use std::io::Write;
+            let output = u64::to_le_bytes(packing.len() as u64).to_vec();
+            let quality = (options.Level.max(0) as u32).min(11);
+            let mut encoder = brotli::CompressorWriter::new(output, 4096, quality, 22);
+            encoder
+                .write_all(packing)
+                .map(|_| encoder.into_inner())
+                .map_err(|err| ZpngError::Backend(backend, err))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(ZpngError::BackendDisabled(backend)),
+    }
+}
 
-// File format header
-// TODO zerocopy or rather less dependencies?
-struct ZPNG_Header {
-    _Magic: u16,
-    _Width: u16,
-    _Height: u16,
-    _Channels: u8,
-    _BytesPerChannel: u8,
+/// Decompresses `payload` into a buffer of exactly `byteCount` bytes
+///
+/// `CheckPayload` has to have accepted the payload for `byteCount` bytes.
+#[allow(unused_variables)]
+fn EntropyDecompress(
+    backend: EntropyBackend,
+    payload: &[u8],
+    byteCount: usize,
+) -> Result<Vec<u8>, ZpngError> {
+    // no ratio limit protects against a brotli payload claiming more than it holds
+    #[cfg(feature = "brotli")]
+    {
+        if backend == EntropyBackend::Brotli {
+            let decoder = brotli::Decompressor::new(&payload[8..], 4096);
+            return ReadAll(decoder, byteCount).map_err(|err| ZpngError::Backend(backend, err));
+        }
+    }
+
+    // zstd can crash on corrupt frames when handed an empty destination, so always give it at
+    // least one byte, an empty image then has to decompress to exactly zero bytes
+    let mut packing = vec![0; byteCount.max(1)];
+    let size: usize = match backend {
+        #[cfg(any(feature = "zstd-safe", feature = "ruzstd"))]
+        EntropyBackend::Zstd => ZstdDecompress(payload, &mut packing, byteCount),
+        #[cfg(feature = "lz4")]
+        EntropyBackend::Lz4 => {
+            lz4_flex::block::decompress_into(&payload[8..], &mut packing[..byteCount]).map_err(
+                |err| ZpngError::Backend(backend, io::Error::new(io::ErrorKind::InvalidData, err)),
+            )
+        }
+        #[cfg(feature = "deflate")]
+        EntropyBackend::Deflate => {
+            let decoder = flate2::read::DeflateDecoder::new(&payload[8..]);
+            ReadExact(decoder, &mut packing[..byteCount])
+                .map_err(|err| ZpngError::Backend(backend, err))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(ZpngError::BackendDisabled(backend)),
+    }?;
+    if size != byteCount {
+        return Err(ZpngError::SizeMismatch {
+            Expected: byteCount as u64,
+            Actual: size as u64,
+        });
+    }
+    packing.truncate(byteCount);
+    Ok(packing)
 }
 
-//------------------------------------------------------------------------------
-// Image Processing
+/// Reads exactly `byteCount` bytes from a streaming decoder, which has to end right after them
+///
+/// The buffer grows as the data comes in, so a payload claiming more than it holds can't make
+/// this allocate more than it decodes.
+#[cfg(feature = "brotli")]
+fn ReadAll(decoder: impl io::Read, byteCount: usize) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut packing = Vec::new();
+    decoder
+        .take(byteCount as u64 + 1)
+        .read_to_end(&mut packing)?;
+    if packing.len() < byteCount {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "less data than the recorded size",
+        ));
+    }
+    if packing.len() > byteCount {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "more data than the recorded size",
+        ));
+    }
+    Ok(packing)
+}
 
-// Interleaving is a 1% compression win, and a 0.3% performance win: Not used.
-// Splitting the data into blocks of 4 at a time actually reduces compression.
+/// Fills `packing` from a streaming decoder, which has to end right after it
+#[cfg(any(feature = "deflate", feature = "ruzstd"))]
+fn ReadExact(mut decoder: impl io::Read, packing: &mut [u8]) -> io::Result<usize> {
+    decoder.read_exact(packing)?;
+    if decoder.read(&mut [0])? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "more data than the recorded size",
+        ));
+    }
+    Ok(packing.len())
+}
 
-fn PackAndFilter<const kChannels: usize>(
-    mut input: &[u8],
-    width: u16,
-    height: u16,
+/// Checks that `payload` records `packedBytes` bytes of filtered data, so a header claiming a
+/// huge image with a few bytes behind it is rejected before anything is allocated
+fn CheckPayload(
+    backend: EntropyBackend,
+    payload: &[u8],
+    packedBytes: u64,
+) -> Result<(), ZpngError> {
+    let contentSize = if backend == EntropyBackend::Zstd {
+        match ZstdPayloadSize(payload, packedBytes)? {
+            Some(contentSize) => contentSize,
+            // without a recorded size only the ratio limit bounds it
+            None => return Ok(()),
+        }
+    } else {
+        ContentSize(backend, payload)?
+    };
+
+    if contentSize != packedBytes {
+        return Err(ZpngError::SizeMismatch {
+            Expected: packedBytes,
+            Actual: contentSize,
+        });
+    }
+    Ok(())
+}
+
+/// Size of the filtered data in a compressed payload, without decompressing it
+fn ContentSize(backend: EntropyBackend, payload: &[u8]) -> Result<u64, ZpngError> {
+    if backend == EntropyBackend::Zstd {
+        return ZstdContentSize(payload);
+    }
+
+    if payload.len() < 8 {
+        return Err(ZpngError::Backend(
+            backend,
+            io::Error::new(io::ErrorKind::UnexpectedEof, "missing content size"),
+        ));
+    }
+    let mut size = [0; 8];
+    size.copy_from_slice(&payload[..8]);
+    Ok(u64::from_le_bytes(size))
+}
+
+#[cfg(feature = "zstd-safe")]
+fn ZstdCompress(packing: &[u8], options: &CompressOptions) -> Result<Vec<u8>, ZpngError> {
+    let threads = if options.Threads == 0 {
+        num_cpus::get() as u32
+    } else {
+        options.Threads
+    };
+
+    let mut cctx = zstd_safe::create_cctx();
+    let parameters = [
+        CParameter::CompressionLevel(options.Level),
+        CParameter::WindowLog(options.WindowLog),
+        CParameter::EnableLongDistanceMatching(options.LongDistanceMatching),
+        CParameter::NbWorkers(threads),
+        // lets the decoder check the size before decompressing
+        CParameter::ContentSizeFlag(true),
+    ];
+    for &parameter in &parameters {
+        zstd_safe::cctx_set_parameter(&mut cctx, parameter).map_err(ZstdError)?;
+    }
+
+    // streaming only knows the content size when told up front
+    zstd_safe::cctx_set_pledged_src_size(&mut cctx, packing.len() as u64).map_err(ZstdError)?;
+
+    // compressing everything in one call sizes the jobs from the number of workers, streaming
+    // the input cuts it into jobs of the size picked for the level and window instead
+    let mut output = vec![0; zstd_safe::compress_bound(packing.len())];
+    let mut outBuffer = zstd_safe::OutBuffer::around(&mut output);
+    let mut inBuffer = zstd_safe::InBuffer::around(packing);
+    while inBuffer.pos < packing.len() {
+        zstd_safe::compress_stream(&mut cctx, &mut outBuffer, &mut inBuffer).map_err(ZstdError)?;
+    }
+    while zstd_safe::end_stream(&mut cctx, &mut outBuffer).map_err(ZstdError)? != 0 {}
+    let size = outBuffer.pos;
+    output.truncate(size);
+    Ok(output)
+}
+
+// The reference Zpng and this crate both store a regular zstd frame. A bare compressed block (the
+// output of ZSTD_compressBlock) has no magic or size, since it can hold at most 128 KiB it is
+// only accepted for small images and gets a frame header put in front of it before decoding.
+#[cfg(any(feature = "zstd-safe", feature = "ruzstd"))]
+fn ZstdDecompress(
+    payload: &[u8],
+    packing: &mut [u8],
     byteCount: usize,
-) -> Vec<u8> {
-    let mut output = vec![0; byteCount];
-    let mut output_offset = 0;
+) -> Result<usize, ZpngError> {
+    let magic = if payload.len() >= 4 {
+        u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]])
+    } else {
+        0
+    };
 
-    for _y in 0..height {
-        let mut prev = [0; kChannels];
+    // the sizes were checked by ZstdPayloadSize
+    if magic == kZstdMagic || magic & 0xFFFF_FFF0 == kZstdSkippableMagic {
+        ZstdDecompressFrames(payload, packing, byteCount)
+    } else {
+        // Frame_Header_Descriptor: 8 byte Frame_Content_Size, Single_Segment_flag
+        let mut frame = Vec::with_capacity(payload.len() + 16);
+        frame.extend_from_slice(&u32::to_le_bytes(kZstdMagic));
+        frame.push(0b1110_0000);
+        frame.extend_from_slice(&u64::to_le_bytes(byteCount as u64));
+        // Block_Header: Last_Block, Block_Type Compressed_Block, Block_Size
+        let blockHeader = 1 | 2 << 1 | (payload.len() as u32) << 3;
+        frame.extend_from_slice(&u32::to_le_bytes(blockHeader)[..3]);
+        frame.extend_from_slice(payload);
+
+        ZstdDecompressFrames(&frame, packing, byteCount)
+    }
+}
 
-        for _x in 0..width {
-            // For each channel:
-            for i in 0..kChannels {
-                let a: u8 = input[i];
-                let d: u8 = a.wrapping_sub(prev[i]);
-                output[i + output_offset] = d;
-                prev[i] = a;
-            }
-            input = &input[kChannels..];
-            output_offset += kChannels;
+/// Size of the content a zstd payload decompresses to, `None` if the frame does not record it
+///
+/// A bare block has no size of its own, it is checked against `packedBytes` instead.
+fn ZstdPayloadSize(payload: &[u8], packedBytes: u64) -> Result<Option<u64>, ZpngError> {
+    let frame = SkipSkippableFrames(payload)?;
+    if frame.len() >= 4
+        && u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) == kZstdMagic
+    {
+        ZstdFrameContentSize(frame)
+    } else if frame.len() < payload.len() {
+        // skippable frames followed by something else, which the decoder rejects
+        Ok(None)
+    } else if payload.is_empty() {
+        Err(ZpngError::Zstd(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "no compressed data",
+        )))
+    } else if packedBytes > kMaxBlockBytes as u64 || payload.len() > kMaxBlockBytes {
+        Err(ZpngError::Zstd(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no zstd frame and too large for a single block",
+        )))
+    } else {
+        Ok(Some(packedBytes))
+    }
+}
+
+/// The part of a zstd payload after any skippable frames
+fn SkipSkippableFrames(mut frames: &[u8]) -> Result<&[u8], ZpngError> {
+    while frames.len() >= 8
+        && u32::from_le_bytes([frames[0], frames[1], frames[2], frames[3]]) & 0xFFFF_FFF0
+            == kZstdSkippableMagic
+    {
+        let size = u32::from_le_bytes([frames[4], frames[5], frames[6], frames[7]]) as usize;
+        if size > frames.len() - 8 {
+            return Err(ZpngError::Zstd(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated skippable frame",
+            )));
         }
+        frames = &frames[8 + size..];
     }
+    Ok(frames)
+}
 
-    debug_assert_eq!(output_offset, output.len());
-    output
+/// Size of the content of a zstd frame, taken from its header
+fn ZstdContentSize(payload: &[u8]) -> Result<u64, ZpngError> {
+    match ZstdFrameContentSize(SkipSkippableFrames(payload)?)? {
+        Some(contentSize) => Ok(contentSize),
+        None => Err(ZpngError::Zstd(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no zstd frame with a content size",
+        ))),
+    }
 }
 
-/// #ifdef ENABLE_RGB_COLOR_FILTER
-fn PackAndFilter_3(mut input: &[u8], width: u16, height: u16, byteCount: usize) -> Vec<u8> {
-    let mut output = vec![0; byteCount];
-    const kChannels: usize = 3;
+/// Content size from a zstd frame header, `None` if the frame does not record it
+#[cfg(all(feature = "zstd-safe", not(feature = "ruzstd")))]
+fn ZstdFrameContentSize(payload: &[u8]) -> Result<Option<u64>, ZpngError> {
+    match zstd_safe::get_frame_content_size(payload) {
+        zstd_safe::CONTENTSIZE_ERROR => Err(ZpngError::Zstd(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid zstd frame header",
+        ))),
+        zstd_safe::CONTENTSIZE_UNKNOWN => Ok(None),
+        contentSize => Ok(Some(contentSize)),
+    }
+}
 
-    // Color plane split
-    let planeBytes = width as usize * height as usize;
-    let mut output_y_offset = 0;
-    let mut output_u_offset = planeBytes;
-    let mut output_v_offset = planeBytes * 2;
+#[cfg(all(feature = "zstd-safe", not(feature = "ruzstd")))]
+fn ZstdDecompressFrames(
+    frames: &[u8],
+    packing: &mut [u8],
+    _byteCount: usize,
+) -> Result<usize, ZpngError> {
+    zstd_safe::decompress(packing, frames).map_err(ZstdError)
+}
 
-    for _row in 0..height {
-        let mut prev = [0; kChannels];
+#[cfg(feature = "zstd-safe")]
+fn ZstdError(code: usize) -> ZpngError {
+    ZpngError::Zstd(io::Error::new(
+        io::ErrorKind::Other,
+        zstd_safe::get_error_name(code),
+    ))
+}
 
-        for _x in 0..width {
-            let mut r: u8 = input[0];
-            let mut g: u8 = input[1];
-            let mut b: u8 = input[2];
+// The ruzstd feature decodes with pure Rust code even if the C library is linked for compression.
 
-            r = r.wrapping_sub(prev[0]);
-            g = g.wrapping_sub(prev[1]);
-            b = b.wrapping_sub(prev[2]);
+/// Content size from a zstd frame header, `None` if the frame does not record it
+#[cfg(feature = "ruzstd")]
+fn ZstdFrameContentSize(payload: &[u8]) -> Result<Option<u64>, ZpngError> {
+    let (frame, _) = ruzstd::frame::read_frame_header(&mut &payload[..]).map_err(RuzstdError)?;
+    frame.check_valid().map_err(RuzstdError)?;
+    if frame.header.descriptor.frame_content_size_bytes() == Ok(0) {
+        return Ok(None);
+    }
+    frame
+        .header
+        .frame_content_size()
+        .map(Some)
+        .map_err(RuzstdError)
+}
 
-            prev[0] = input[0];
-            prev[1] = input[1];
-            prev[2] = input[2];
+/// Decodes a single zstd frame, skippable frames in front of it are left out like the C library
+/// does
+#[cfg(feature = "ruzstd")]
+fn ZstdDecompressFrames(
+    frames: &[u8],
+    packing: &mut [u8],
+    byteCount: usize,
+) -> Result<usize, ZpngError> {
+    let mut frames = SkipSkippableFrames(frames)?;
+    let mut decoder = ruzstd::StreamingDecoder::new(&mut frames).map_err(RuzstdError)?;
+    ReadExact(&mut decoder, &mut packing[..byteCount]).map_err(ZpngError::Zstd)
+}
 
-            // GB-RG filter from BCIF
-            let y: u8 = b;
-            let u: u8 = g.wrapping_sub(b);
-            let v: u8 = g.wrapping_sub(r);
+#[cfg(feature = "ruzstd")]
+fn RuzstdError(message: String) -> ZpngError {
+    ZpngError::Zstd(io::Error::new(io::ErrorKind::InvalidData, message))
+}
 
-            output[output_y_offset] = y;
-            output_y_offset += 1;
+#[cfg(not(any(feature = "zstd-safe", feature = "ruzstd")))]
+fn ZstdFrameContentSize(_payload: &[u8]) -> Result<Option<u64>, ZpngError> {
+    Err(ZpngError::BackendDisabled(EntropyBackend::Zstd))
+}
 
-            output[output_u_offset] = u;
-            output_u_offset += 1;
+fn CheckLimit(limit: &'static str, value: u64, max: u64) -> Result<(), ZpngError> {
+    if value > max {
+        return Err(ZpngError::LimitExceeded {
+            Limit: limit,
+            Value: value,
+            Max: max,
+        });
+    }
+    Ok(())
+}
 
-            output[output_v_offset] = v;
-            output_v_offset += 1;
+/// Every layout with 1 to 8 bytes per pixel can be filtered, as well as up to 4 channels of 4
+/// bytes each (e.g. RGBA f32). Custom filters are handed any layout. This is checked before
+/// touching the pixel data so that a corrupt header is reported instead of panicking later on
+fn IsSupportedFormat(channels: u8, bytesPerChannel: u8, flags: u8) -> bool {
+    let pixelBytes = bytesPerChannel as u32 * channels as u32;
+    channels != 0
+        && bytesPerChannel != 0
+        && (pixelBytes <= 8
+            || (bytesPerChannel == 4 && channels <= 4)
+            || flags & ZPNG_FLAG_CUSTOM_FILTER != 0)
+}
 
-            input = &input[kChannels..];
+/// Whether a color filter can be used for a layout, they need at least three color channels
+fn ColorFilterApplies(
+    colorFilter: ZPNG_ColorFilter,
+    channels: u8,
+    bytesPerChannel: u8,
+    flags: u8,
+) -> bool {
+    match colorFilter {
+        ZPNG_ColorFilter::None => true,
+        _ if flags & ZPNG_FLAG_16BIT_SAMPLES != 0 => channels == 3 || channels == 4,
+        _ if flags & ZPNG_FLAG_32BIT_SAMPLES != 0 => false,
+        // the byte filters only look at the pixel size, just like the reference Zpng
+        ZPNG_ColorFilter::GbRg => {
+            let pixelBytes = bytesPerChannel as u32 * channels as u32;
+            pixelBytes == 3 || pixelBytes == 4
         }
+        ZPNG_ColorFilter::YCoCgR => bytesPerChannel == 1 && (channels == 3 || channels == 4),
     }
-    output
 }
 
-/// #ifdef ENABLE_RGB_COLOR_FILTER
-fn PackAndFilter_4(mut input: &[u8], width: u16, height: u16, byteCount: usize) -> Vec<u8> {
-    let mut output = vec![0; byteCount];
-    const kChannels: usize = 4;
+/// The original header has no room for the color filter, the reference Zpng always uses GB-RG
+fn ColorFilterV1(channels: u8, bytesPerChannel: u8) -> ZPNG_ColorFilter {
+    if ColorFilterApplies(ZPNG_ColorFilter::GbRg, channels, bytesPerChannel, 0) {
+        ZPNG_ColorFilter::GbRg
+    } else {
+        ZPNG_ColorFilter::None
+    }
+}
 
-    // Color plane split
-    let planeBytes = width as usize * height as usize;
-    let mut output_y_offset = 0;
-    let mut output_u_offset = planeBytes;
-    let mut output_v_offset = planeBytes * 2;
-    let mut output_a_offset = planeBytes * 3;
+fn PredictorFromByte(value: u8) -> Option<ZPNG_Predictor> {
+    match value {
+        0 => Some(ZPNG_Predictor::Left),
+        1 => Some(ZPNG_Predictor::Up),
+        2 => Some(ZPNG_Predictor::Average),
+        3 => Some(ZPNG_Predictor::Paeth),
+        4 => Some(ZPNG_Predictor::Med),
+        5 => Some(ZPNG_Predictor::Adaptive),
+        _ => None,
+    }
+}
+
+/// Number of bands of `kPredictorBandRows` rows, each has its own predictor
+fn PredictorBands(height: u32) -> usize {
+    let height = height as usize;
+    match height {
+        0 => 0,
+        _ => (height - 1) / kPredictorBandRows + 1,
+    }
+}
+
+const kCompressionLevel: i32 = 1;
+
+// zstd framing, a block decodes to at most 128 KiB and needs at least 4 bytes for that
+const kMaxBlockBytes: usize = 128 << 10;
+const kZstdMagic: u32 = 0xFD2F_B528;
+const kZstdSkippableMagic: u32 = 0x184D_2A50;
+
+// above what zstd blocks (32768:1), lz4 (255:1) and deflate (1032:1) can expand to
+const kDefaultMaxRatio: u64 = 1 << 16;
+
+const kPredictorBandRows: usize = 16;
+const kAdaptivePredictors: [ZPNG_Predictor; 5] = [
+    ZPNG_Predictor::Left,
+    ZPNG_Predictor::Up,
+    ZPNG_Predictor::Average,
+    ZPNG_Predictor::Paeth,
+    ZPNG_Predictor::Med,
+];
+
+/// Magic of the original header with 16 bit dimensions, compatible with the reference Zpng
+pub const ZPNG_HEADER_MAGIC: u16 = 0xFBF8;
+pub const ZPNG_HEADER_OVERHEAD_BYTES: u8 = 8;
+
+/// Magic of the header with 32 bit dimensions and flags
+pub const ZPNG_HEADER_MAGIC_V2: u16 = 0xFBF9;
+pub const ZPNG_HEADER_V2_OVERHEAD_BYTES: u8 = 16;
+
+/// Header flag, 2 byte channels were filtered as 16 bit samples and stored as high and low byte
+/// planes
+pub const ZPNG_FLAG_16BIT_SAMPLES: u8 = 1;
+
+/// Header flag, 4 byte channels were XOR filtered as 32 bit samples and stored as byte planes
+pub const ZPNG_FLAG_32BIT_SAMPLES: u8 = 2;
+
+/// Header flag, the image was transformed by a custom `Filter`, whose ID is stored in place of
+/// the color filter and predictor
+pub const ZPNG_FLAG_CUSTOM_FILTER: u8 = 4;
+
+/// File format header
+///
+/// Both versions are little endian, the magic decides which layout follows:
+///
+/// ```text
+/// ZPNG_HEADER_MAGIC:    Magic u16, Width u16, Height u16, Channels u8, BytesPerChannel u8
+/// ZPNG_HEADER_MAGIC_V2: Magic u16, Channels u8, BytesPerChannel u8, Width u32, Height u32,
+///                       Flags u8, ColorFilter u8, Predictor u8, EntropyBackend u8
+///
+/// With `ZPNG_FLAG_CUSTOM_FILTER` the color filter and predictor are replaced by the u16 ID of
+/// the filter.
+/// ```
+// TODO zerocopy or rather less dependencies?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZPNG_Header {
+    /// Either `ZPNG_HEADER_MAGIC` or `ZPNG_HEADER_MAGIC_V2`
+    pub Magic: u16,
+
+    /// Width in pixels of image
+    pub Width: u32,
+
+    /// Height in pixels of image
+    pub Height: u32,
+
+    /// Number of channels for each pixel
+    pub Channels: u8,
+
+    /// Number of bytes for each color channel
+    pub BytesPerChannel: u8,
+
+    /// `ZPNG_FLAG_*` bits, always zero in the original header
+    pub Flags: u8,
+
+    /// Color transform of the image, implied by the layout in the original header
+    pub ColorFilter: ZPNG_ColorFilter,
+
+    /// Spatial predictor of the image, always `ZPNG_Predictor::Left` in the original header
+    pub Predictor: ZPNG_Predictor,
+
+    /// ID of the custom `Filter` the image was transformed with, if any
+    pub CustomFilter: Option<u16>,
+
+    /// Coder of the filtered data, always zstd in the original header
+    pub EntropyBackend: EntropyBackend,
+}
+
+/// Reversible color transforms for images with three or more channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ZPNG_ColorFilter {
+    /// Channels are filtered independently
+    None = 0,
+
+    /// GB-RG filter from BCIF: stores B, G - B and G - R
+    GbRg = 1,
+
+    /// Lossless YCoCg-R from JPEG XR, decorrelates better than GB-RG on photos
+    ///
+    /// Co and Cg need one more bit than the input, so they are stored with an extra byte plane
+    /// each. Only used for 8 bit channels and 16 bit samples, 16 bit channels are always
+    /// filtered as samples with it.
+    YCoCgR = 2,
+}
+
+/// Spatial predictors, each sample is stored as the difference to its prediction
+///
+/// Every predictor but `Left` looks at the row above, so the decoder has to unfilter the rows one
+/// after another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ZPNG_Predictor {
+    /// Left neighbor, what the reference Zpng does
+    Left = 0,
+
+    /// Upper neighbor
+    Up = 1,
+
+    /// Average of the left and upper neighbors, rounded down
+    Average = 2,
+
+    /// Paeth predictor from PNG
+    Paeth = 3,
+
+    /// Median edge detector from LOCO-I (JPEG-LS)
+    Med = 4,
+
+    /// One of the above for every band of 16 rows, whichever gives the smallest sum of absolute
+    /// residuals. The choices are stored as one byte per band after the filtered data.
+    Adaptive = 5,
+}
+
+/// Entropy coders for the filtered data
+///
+/// Besides zstd these are optional cargo features (`lz4`, `deflate` and `brotli`). Their
+/// compressed stream is preceded by the size of the filtered data as a little endian u64, zstd
+/// keeps it in its frame header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EntropyBackend {
+    /// zstd frame, what the reference Zpng uses
+    Zstd = 0,
+
+    /// lz4 block, compresses less but decodes much faster
+    Lz4 = 1,
+
+    /// Raw deflate stream, for environments where zstd is not an option
+    Deflate = 2,
+
+    /// Brotli stream, slow to compress but usually smaller than deflate
+    Brotli = 3,
+}
+
+impl ZPNG_Header {
+    /// Number of pixels in the image
+    pub fn PixelCount(&self) -> u64 {
+        self.Width as u64 * self.Height as u64
+    }
+
+    /// Size in bytes of the decompressed pixel data
+    pub fn DecodedBytes(&self) -> u64 {
+        self.PixelCount()
+            .saturating_mul(self.Channels as u64 * self.BytesPerChannel as u64)
+    }
+
+    /// Size in bytes of the filtered data inside the zstd frame
+    ///
+    /// A custom filter can produce any amount of data, which is then only known from the zstd
+    /// frame. The decoded size is returned for those as an estimate.
+    pub fn PackedBytes(&self) -> u64 {
+        let mut bytes = self.DecodedBytes();
+        if self.ColorFilter == ZPNG_ColorFilter::YCoCgR {
+            // one extra byte plane each for Co and Cg
+            bytes = bytes.saturating_add(self.PixelCount().saturating_mul(2));
+        }
+        if self.Predictor == ZPNG_Predictor::Adaptive {
+            bytes = bytes.saturating_add(PredictorBands(self.Height) as u64);
+        }
+        bytes
+    }
+
+    /// Peak number of bytes `ZPNG_Decompress` allocates for this image
+    pub fn ExpectedMemory(&self) -> u64 {
+        // the packing buffer and the unfiltered output are both allocated at full size
+        self.PackedBytes().saturating_add(self.DecodedBytes())
+    }
+
+    /// Size in bytes of the header in the file, the compressed data starts right after it
+    pub fn OverheadBytes(&self) -> usize {
+        if self.Magic == ZPNG_HEADER_MAGIC_V2 {
+            ZPNG_HEADER_V2_OVERHEAD_BYTES as usize
+        } else {
+            ZPNG_HEADER_OVERHEAD_BYTES as usize
+        }
+    }
+}
 
-    for _row in 0..height {
-        let mut prev = [0; kChannels];
+fn WriteHeader(header: &ZPNG_Header) -> Vec<u8> {
+    let mut data = Vec::<u8>::with_capacity(header.OverheadBytes());
+
+    data.extend_from_slice(&u16::to_le_bytes(header.Magic));
+    if header.Magic == ZPNG_HEADER_MAGIC_V2 {
+        data.extend_from_slice(&u8::to_le_bytes(header.Channels));
+        data.extend_from_slice(&u8::to_le_bytes(header.BytesPerChannel));
+        data.extend_from_slice(&u32::to_le_bytes(header.Width));
+        data.extend_from_slice(&u32::to_le_bytes(header.Height));
+        data.extend_from_slice(&u8::to_le_bytes(header.Flags));
+        if let Some(id) = header.CustomFilter {
+            data.extend_from_slice(&u16::to_le_bytes(id));
+        } else {
+            data.extend_from_slice(&u8::to_le_bytes(header.ColorFilter as u8));
+            data.extend_from_slice(&u8::to_le_bytes(header.Predictor as u8));
+        }
+        data.extend_from_slice(&u8::to_le_bytes(header.EntropyBackend as u8));
+    } else {
+        data.extend_from_slice(&u16::to_le_bytes(header.Width as u16));
+        data.extend_from_slice(&u16::to_le_bytes(header.Height as u16));
+        data.extend_from_slice(&u8::to_le_bytes(header.Channels));
+        data.extend_from_slice(&u8::to_le_bytes(header.BytesPerChannel));
+    }
 
-        for _x in 0..width {
-            let mut r: u8 = input[0];
-            let mut g: u8 = input[1];
-            let mut b: u8 = input[2];
-            let mut a: u8 = input[3];
+    debug_assert_eq!(data.len(), header.OverheadBytes());
+    data
+}
 
-            r = r.wrapping_sub(prev[0]);
-            g = g.wrapping_sub(prev[1]);
-            b = b.wrapping_sub(prev[2]);
-            a = a.wrapping_sub(prev[3]);
+//------------------------------------------------------------------------------
+// Image Processing
 
-            prev[0] = input[0];
-            prev[1] = input[1];
-            prev[2] = input[2];
-            prev[3] = input[3];
+// Interleaving is a 1% compression win, and a 0.3% performance win: Not used.
+// Splitting the data into blocks of 4 at a time actually reduces compression.
+
+// Filtering only reads the input, so rows can be filtered independently of each other. The same
+// goes for unfiltering with the left predictor, every other predictor needs the row above to be
+// finished first. With the rayon feature independent rows are spread over all cores.
+// The chunk size is kept at one or more because images with a width of zero have no rows to visit.
+#[cfg(feature = "rayon")]
+macro_rules! Rows {
+    ($slice:expr, $rowBytes:expr) => {
+        $slice.par_chunks(($rowBytes).max(1))
+    };
+}
 
-            // GB-RG filter from BCIF
-            let y: u8 = b;
-            let u: u8 = g.wrapping_sub(b);
-            let v: u8 = g.wrapping_sub(r);
+#[cfg(feature = "rayon")]
+macro_rules! RowsMut {
+    ($slice:expr, $rowBytes:expr) => {
+        $slice.par_chunks_mut(($rowBytes).max(1))
+    };
+}
 
-            output[output_y_offset] = y;
-            output_y_offset += 1;
+#[cfg(not(feature = "rayon"))]
+macro_rules! Rows {
+    ($slice:expr, $rowBytes:expr) => {
+        $slice.chunks(($rowBytes).max(1))
+    };
+}
 
-            output[output_u_offset] = u;
-            output_u_offset += 1;
+#[cfg(not(feature = "rayon"))]
+macro_rules! RowsMut {
+    ($slice:expr, $rowBytes:expr) => {
+        $slice.chunks_mut(($rowBytes).max(1))
+    };
+}
 
-            output[output_v_offset] = v;
-            output_v_offset += 1;
+/// Predicts a sample from its left (a), upper (b) and upper left (c) neighbors
+///
+/// Neighbors outside of the image are zero, like in PNG.
+fn Predict<T: Into<i32>>(predictor: ZPNG_Predictor, a: T, b: T, c: T) -> i32 {
+    let (a, b, c) = (a.into(), b.into(), c.into());
+    match predictor {
+        ZPNG_Predictor::Left => a,
+        ZPNG_Predictor::Up => b,
+        ZPNG_Predictor::Average => (a + b) >> 1,
+        ZPNG_Predictor::Paeth => {
+            let p = a + b - c;
+            let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+            if pa <= pb && pa <= pc {
+                a
+            } else if pb <= pc {
+                b
+            } else {
+                c
+            }
+        }
+        ZPNG_Predictor::Med => {
+            if c >= a.max(b) {
+                a.min(b)
+            } else if c <= a.min(b) {
+                a.max(b)
+            } else {
+                a + b - c
+            }
+        }
+        ZPNG_Predictor::Adaptive => unreachable!("resolved for every band"),
+    }
+}
 
-            output[output_a_offset] = a;
-            output_a_offset += 1;
+/// Picks the predictor with the smallest sum of absolute residuals for every band of rows
+///
+/// The image is looked at as `samples` interleaved samples per pixel of `sampleBytes` bytes each,
+/// ignoring any color filter, which is close enough to pick a good predictor.
+fn ChoosePredictors(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    samples: usize,
+    sampleBytes: usize,
+) -> Vec<ZPNG_Predictor> {
+    let rowSamples = width as usize * samples;
+    let rowBytes = rowSamples * sampleBytes;
+    if rowBytes == 0 {
+        return vec![ZPNG_Predictor::Left; PredictorBands(height)];
+    }
 
-            input = &input[kChannels..];
+    let zeros = vec![0; rowBytes];
+    let rowAbove = |row: usize| match row {
+        0 => &zeros[..],
+        _ => &input[(row - 1) * rowBytes..row * rowBytes],
+    };
+    let sample = |row: &[u8], j: usize| {
+        let mut value = 0i32;
+        for byte in 0..sampleBytes {
+            value |= (row[j * sampleBytes + byte] as i32) << (8 * byte);
+        }
+        value
+    };
+    // residuals wrap around like the stored ones, so look at them as signed samples
+    let unused = 32 - 8 * sampleBytes as u32;
+
+    Rows!(input, rowBytes * kPredictorBandRows)
+        .enumerate()
+        .map(|(band, rows)| {
+            let mut costs = [0i64; kAdaptivePredictors.len()];
+
+            for (r, current) in rows.chunks(rowBytes).enumerate() {
+                let up = rowAbove(band * kPredictorBandRows + r);
+                for j in 0..rowSamples {
+                    let (a, c) = if j >= samples {
+                        (sample(current, j - samples), sample(up, j - samples))
+                    } else {
+                        (0, 0)
+                    };
+                    let (b, x) = (sample(up, j), sample(current, j));
+
+                    for (cost, &predictor) in costs.iter_mut().zip(&kAdaptivePredictors) {
+                        let d = x.wrapping_sub(Predict(predictor, a, b, c)) << unused >> unused;
+                        *cost += d.abs() as i64;
+                    }
+                }
+            }
+
+            let best = (0..costs.len()).min_by_key(|&i| costs[i]).unwrap();
+            kAdaptivePredictors[best]
+        })
+        .collect()
+}
+
+/// Calls `unfilter(row, output, up)` for every row of the output, `up` is the unfiltered row
+/// above (zeros for the first row)
+fn UnfilterRows<F>(output: &mut [u8], rowBytes: usize, predictors: &[ZPNG_Predictor], unfilter: F)
+where
+    F: Fn(usize, &mut [u8], &[u8]) + Send + Sync,
+{
+    let zeros = vec![0; rowBytes];
+    if predictors
+        .iter()
+        .all(|&predictor| predictor == ZPNG_Predictor::Left)
+    {
+        RowsMut!(output, rowBytes)
+            .enumerate()
+            .for_each(|(row, output)| unfilter(row, output, &zeros));
+    } else {
+        let mut up: &[u8] = &zeros;
+        for (row, output) in output.chunks_mut(rowBytes.max(1)).enumerate() {
+            unfilter(row, output, up);
+            up = output;
         }
     }
+}
+
+fn PackAndFilter<const kChannels: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    predictors: &[ZPNG_Predictor],
+) -> Vec<u8> {
+    let mut output = vec![0; byteCount];
+    let rowBytes = width as usize * kChannels;
+    debug_assert_eq!(rowBytes * height as usize, output.len());
+
+    let zeros = vec![0; rowBytes];
+    let rowAbove = |row: usize| match row {
+        0 => &zeros[..],
+        _ => &input[(row - 1) * rowBytes..row * rowBytes],
+    };
+
+    RowsMut!(output, rowBytes)
+        .zip(Rows!(input, rowBytes))
+        .enumerate()
+        .for_each(|(row, (output, input))| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let mut left = [0; kChannels];
+            let mut upLeft = [0; kChannels];
+
+            for ((output, input), up) in output
+                .chunks_mut(kChannels)
+                .zip(input.chunks(kChannels))
+                .zip(rowAbove(row).chunks(kChannels))
+            {
+                // For each channel:
+                for i in 0..kChannels {
+                    let a: u8 = input[i];
+                    let p = Predict(predictor, left[i], up[i], upLeft[i]) as u8;
+                    let d: u8 = a.wrapping_sub(p);
+                    output[i] = d;
+                    left[i] = a;
+                    upLeft[i] = up[i];
+                }
+            }
+        });
+
     output
 }
 
-fn UnpackAndUnfilter<const kChannels: usize>(
-    mut input: &[u8],
-    width: u16,
-    height: u16,
+/// ZPNG_ColorFilter::GbRg
+fn PackAndFilter_3(
+    input: &[u8],
+    width: u32,
+    height: u32,
     byteCount: usize,
-) -> ZPNG_Buffer {
+    predictors: &[ZPNG_Predictor],
+) -> Vec<u8> {
     let mut output = vec![0; byteCount];
-    let mut output_offset = 0;
+    const kChannels: usize = 3;
 
-    for _y in 0..height {
-        let mut prev = [0; kChannels];
+    // Color plane split
+    let planeBytes = width as usize * height as usize;
+    let (output_y, rest) = output.split_at_mut(planeBytes);
+    let (output_u, output_v) = rest.split_at_mut(planeBytes);
+    let width = width as usize;
+
+    let rowBytes = width * kChannels;
+    let zeros = vec![0; rowBytes];
+    let rowAbove = |row: usize| match row {
+        0 => &zeros[..],
+        _ => &input[(row - 1) * rowBytes..row * rowBytes],
+    };
+
+    RowsMut!(output_y, width)
+        .zip(RowsMut!(output_u, width))
+        .zip(RowsMut!(output_v, width))
+        .zip(Rows!(input, rowBytes))
+        .enumerate()
+        .for_each(|(row, (((output_y, output_u), output_v), input))| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let mut left = [0; kChannels];
+            let mut upLeft = [0; kChannels];
+
+            for (x, (input, up)) in input
+                .chunks(kChannels)
+                .zip(rowAbove(row).chunks(kChannels))
+                .enumerate()
+            {
+                let mut d = [0u8; kChannels];
+                for i in 0..kChannels {
+                    let p = Predict(predictor, left[i], up[i], upLeft[i]) as u8;
+                    d[i] = input[i].wrapping_sub(p);
+                    left[i] = input[i];
+                    upLeft[i] = up[i];
+                }
+                let (r, g, b) = (d[0], d[1], d[2]);
+
+                // GB-RG filter from BCIF
+                let y: u8 = b;
+                let u: u8 = g.wrapping_sub(b);
+                let v: u8 = g.wrapping_sub(r);
+
+                output_y[x] = y;
+                output_u[x] = u;
+                output_v[x] = v;
+            }
+        });
+
+    output
+}
+
+/// ZPNG_ColorFilter::GbRg
+fn PackAndFilter_4(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    predictors: &[ZPNG_Predictor],
+) -> Vec<u8> {
+    let mut output = vec![0; byteCount];
+    const kChannels: usize = 4;
+
+    // Color plane split
+    let planeBytes = width as usize * height as usize;
+    let (output_y, rest) = output.split_at_mut(planeBytes);
+    let (output_u, rest) = rest.split_at_mut(planeBytes);
+    let (output_v, output_a) = rest.split_at_mut(planeBytes);
+    let width = width as usize;
+
+    let rowBytes = width * kChannels;
+    let zeros = vec![0; rowBytes];
+    let rowAbove = |row: usize| match row {
+        0 => &zeros[..],
+        _ => &input[(row - 1) * rowBytes..row * rowBytes],
+    };
 
-        for _x in 0..width {
+    RowsMut!(output_y, width)
+        .zip(RowsMut!(output_u, width))
+        .zip(RowsMut!(output_v, width))
+        .zip(RowsMut!(output_a, width))
+        .zip(Rows!(input, rowBytes))
+        .enumerate()
+        .for_each(
+            |(row, ((((output_y, output_u), output_v), output_a), input))| {
+                let predictor = predictors[row / kPredictorBandRows];
+                let mut left = [0; kChannels];
+                let mut upLeft = [0; kChannels];
+
+                for (x, (input, up)) in input
+                    .chunks(kChannels)
+                    .zip(rowAbove(row).chunks(kChannels))
+                    .enumerate()
+                {
+                    let mut d = [0u8; kChannels];
+                    for i in 0..kChannels {
+                        let p = Predict(predictor, left[i], up[i], upLeft[i]) as u8;
+                        d[i] = input[i].wrapping_sub(p);
+                        left[i] = input[i];
+                        upLeft[i] = up[i];
+                    }
+                    let (r, g, b, a) = (d[0], d[1], d[2], d[3]);
+
+                    // GB-RG filter from BCIF
+                    let y: u8 = b;
+                    let u: u8 = g.wrapping_sub(b);
+                    let v: u8 = g.wrapping_sub(r);
+
+                    output_y[x] = y;
+                    output_u[x] = u;
+                    output_v[x] = v;
+                    output_a[x] = a;
+                }
+            },
+        );
+
+    output
+}
+
+fn UnpackAndUnfilter<const kChannels: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    predictors: &[ZPNG_Predictor],
+) -> ZPNG_Buffer {
+    let mut output = vec![0; byteCount];
+    let rowBytes = width as usize * kChannels;
+    debug_assert_eq!(rowBytes * height as usize, output.len());
+
+    UnfilterRows(&mut output, rowBytes, predictors, |row, output, up| {
+        let predictor = predictors[row / kPredictorBandRows];
+        let input = &input[row * rowBytes..(row + 1) * rowBytes];
+        let mut left = [0; kChannels];
+        let mut upLeft = [0; kChannels];
+
+        for ((output, input), up) in output
+            .chunks_mut(kChannels)
+            .zip(input.chunks(kChannels))
+            .zip(up.chunks(kChannels))
+        {
             // For each channel:
             for i in 0..kChannels {
                 let d: u8 = input[i];
-                let a: u8 = d.wrapping_add(prev[i]);
-                output[i + output_offset] = a;
-                prev[i] = a;
+                let p = Predict(predictor, left[i], up[i], upLeft[i]) as u8;
+                let a: u8 = d.wrapping_add(p);
+                output[i] = a;
+                left[i] = a;
+                upLeft[i] = up[i];
             }
-
-            input = &input[kChannels..];
-            output_offset += kChannels;
         }
-    }
-    debug_assert_eq!(output_offset, output.len());
+    });
+
     ZPNG_Buffer { Data: output }
 }
 
-/// #ifdef ENABLE_RGB_COLOR_FILTER
-fn UnpackAndUnfilter_3(input: &[u8], width: u16, height: u16, byteCount: usize) -> ZPNG_Buffer {
+/// ZPNG_ColorFilter::GbRg
+fn UnpackAndUnfilter_3(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    predictors: &[ZPNG_Predictor],
+) -> ZPNG_Buffer {
     let mut output = vec![0; byteCount];
     const kChannels: usize = 3;
 
     // Color plane split
     let planeBytes = width as usize * height as usize;
-    let mut input_y_offset = 0;
-    let mut input_u_offset = planeBytes;
-    let mut input_v_offset = planeBytes * 2;
+    let (input_y, rest) = input.split_at(planeBytes);
+    let (input_u, input_v) = rest.split_at(planeBytes);
+    let width = width as usize;
+
+    UnfilterRows(
+        &mut output,
+        width * kChannels,
+        predictors,
+        |row, output, up| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let offset = row * width;
+            let mut left = [0; kChannels];
+            let mut upLeft = [0; kChannels];
+
+            for (x, (output, up)) in output
+                .chunks_mut(kChannels)
+                .zip(up.chunks(kChannels))
+                .enumerate()
+            {
+                let y: u8 = input_y[offset + x];
+                let u: u8 = input_u[offset + x];
+                let v: u8 = input_v[offset + x];
+
+                // GB-RG filter from BCIF
+                let B: u8 = y;
+                let G: u8 = u.wrapping_add(B);
+                let d = [G.wrapping_sub(v), G, B];
+
+                for i in 0..kChannels {
+                    let p = Predict(predictor, left[i], up[i], upLeft[i]) as u8;
+                    output[i] = d[i].wrapping_add(p);
+                    left[i] = output[i];
+                    upLeft[i] = up[i];
+                }
+            }
+        },
+    );
 
-    let mut output_offset = 0;
+    ZPNG_Buffer { Data: output }
+}
 
-    for _row in 0..height {
-        let mut prev = [0; kChannels];
+/// ZPNG_ColorFilter::GbRg
+fn UnpackAndUnfilter_4(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    predictors: &[ZPNG_Predictor],
+) -> ZPNG_Buffer {
+    let mut output = vec![0; byteCount];
+    const kChannels: usize = 4;
 
-        for _x in 0..width {
-            let y: u8 = input[input_y_offset];
-            input_y_offset += 1;
+    // Color plane split
+    let planeBytes = width as usize * height as usize;
+    let (input_y, rest) = input.split_at(planeBytes);
+    let (input_u, rest) = rest.split_at(planeBytes);
+    let (input_v, input_a) = rest.split_at(planeBytes);
+    let width = width as usize;
+
+    UnfilterRows(
+        &mut output,
+        width * kChannels,
+        predictors,
+        |row, output, up| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let offset = row * width;
+            let mut left = [0; kChannels];
+            let mut upLeft = [0; kChannels];
+
+            for (x, (output, up)) in output
+                .chunks_mut(kChannels)
+                .zip(up.chunks(kChannels))
+                .enumerate()
+            {
+                let y: u8 = input_y[offset + x];
+                let u: u8 = input_u[offset + x];
+                let v: u8 = input_v[offset + x];
+                let a: u8 = input_a[offset + x];
+
+                // GB-RG filter from BCIF
+                let B: u8 = y;
+                let G: u8 = u.wrapping_add(B);
+                let d = [G.wrapping_sub(v), G, B, a];
+
+                for i in 0..kChannels {
+                    let p = Predict(predictor, left[i], up[i], upLeft[i]) as u8;
+                    output[i] = d[i].wrapping_add(p);
+                    left[i] = output[i];
+                    upLeft[i] = up[i];
+                }
+            }
+        },
+    );
 
-            let u: u8 = input[input_u_offset];
-            input_u_offset += 1;
+    ZPNG_Buffer { Data: output }
+}
 
-            let v: u8 = input[input_v_offset];
-            input_v_offset += 1;
+// 16 bit samples are predicted like the bytes above, just with wider arithmetic. The residuals
+// are then split into one plane of high bytes and one of low bytes per channel, the high bytes
+// are mostly zero for smooth images which zstd compresses very well.
+fn PackAndFilter16<const kChannels: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    colorFilter: bool,
+    predictors: &[ZPNG_Predictor],
+) -> Vec<u8> {
+    let rowSamples = width as usize * kChannels;
+    let mut residuals = vec![0u16; byteCount / 2];
+    debug_assert_eq!(rowSamples * height as usize, residuals.len());
+
+    let rowBytes = rowSamples * 2;
+    let zeros = vec![0; rowBytes];
+    let rowAbove = |row: usize| match row {
+        0 => &zeros[..],
+        _ => &input[(row - 1) * rowBytes..row * rowBytes],
+    };
 
-            // GB-RG filter from BCIF
-            let B: u8 = y;
-            let G: u8 = u.wrapping_add(B);
-            let mut r: u8 = G.wrapping_sub(v);
-            let mut g: u8 = G;
-            let mut b: u8 = B;
+    RowsMut!(residuals, rowSamples)
+        .zip(Rows!(input, rowBytes))
+        .enumerate()
+        .for_each(|(row, (residuals, input))| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let mut left = [0u16; kChannels];
+            let mut upLeft = [0u16; kChannels];
+
+            for ((residuals, input), up) in residuals
+                .chunks_mut(kChannels)
+                .zip(input.chunks(kChannels * 2))
+                .zip(rowAbove(row).chunks(kChannels * 2))
+            {
+                for i in 0..kChannels {
+                    let a = u16::from_le_bytes([input[2 * i], input[2 * i + 1]]);
+                    let b = u16::from_le_bytes([up[2 * i], up[2 * i + 1]]);
+                    let p = Predict(predictor, left[i], b, upLeft[i]) as u16;
+                    residuals[i] = a.wrapping_sub(p);
+                    left[i] = a;
+                    upLeft[i] = b;
+                }
+
+                if colorFilter && kChannels >= 3 {
+                    // GB-RG filter from BCIF
+                    let (r, g, b) = (residuals[0], residuals[1], residuals[2]);
+                    residuals[0] = b;
+                    residuals[1] = g.wrapping_sub(b);
+                    residuals[2] = g.wrapping_sub(r);
+                }
+            }
+        });
 
-            r = r.wrapping_add(prev[0]);
-            g = g.wrapping_add(prev[1]);
-            b = b.wrapping_add(prev[2]);
+    // Plane split: high bytes of every channel first, then the low bytes
+    let mut output = vec![0; byteCount];
+    let planeBytes = width as usize * height as usize;
+    RowsMut!(output, planeBytes)
+        .enumerate()
+        .for_each(|(plane, output)| {
+            let channel = plane % kChannels;
+            let shift = if plane < kChannels { 8 } else { 0 };
+            for (x, output) in output.iter_mut().enumerate() {
+                *output = (residuals[x * kChannels + channel] >> shift) as u8;
+            }
+        });
 
-            output[output_offset + 0] = r;
-            output[output_offset + 1] = g;
-            output[output_offset + 2] = b;
+    output
+}
 
-            prev[0] = r;
-            prev[1] = g;
-            prev[2] = b;
+fn UnpackAndUnfilter16<const kChannels: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    colorFilter: bool,
+    predictors: &[ZPNG_Predictor],
+) -> ZPNG_Buffer {
+    let mut output = vec![0; byteCount];
+    let planeBytes = width as usize * height as usize;
+    let rowSamples = width as usize * kChannels;
+    debug_assert_eq!(rowSamples * height as usize * 2, output.len());
+
+    UnfilterRows(
+        &mut output,
+        rowSamples * 2,
+        predictors,
+        |row, output, up| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let mut left = [0u16; kChannels];
+            let mut upLeft = [0u16; kChannels];
+            let rowOffset = row * width as usize;
+
+            for (x, (output, up)) in output
+                .chunks_mut(kChannels * 2)
+                .zip(up.chunks(kChannels * 2))
+                .enumerate()
+            {
+                let mut residuals = [0u16; kChannels];
+                for i in 0..kChannels {
+                    let hi = input[i * planeBytes + rowOffset + x];
+                    let lo = input[(kChannels + i) * planeBytes + rowOffset + x];
+                    residuals[i] = u16::from_be_bytes([hi, lo]);
+                }
+
+                if colorFilter && kChannels >= 3 {
+                    // GB-RG filter from BCIF
+                    let B = residuals[0];
+                    let G = residuals[1].wrapping_add(B);
+                    residuals[0] = G.wrapping_sub(residuals[2]);
+                    residuals[1] = G;
+                    residuals[2] = B;
+                }
+
+                for i in 0..kChannels {
+                    let b = u16::from_le_bytes([up[2 * i], up[2 * i + 1]]);
+                    let p = Predict(predictor, left[i], b, upLeft[i]) as u16;
+                    let a = residuals[i].wrapping_add(p);
+                    output[2 * i..2 * i + 2].copy_from_slice(&a.to_le_bytes());
+                    left[i] = a;
+                    upLeft[i] = b;
+                }
+            }
+        },
+    );
 
-            output_offset += kChannels;
-        }
-    }
-    debug_assert_eq!(output.len(), output_offset);
     ZPNG_Buffer { Data: output }
 }
 
-/// #ifdef ENABLE_RGB_COLOR_FILTER
-fn UnpackAndUnfilter_4(input: &[u8], width: u16, height: u16, byteCount: usize) -> ZPNG_Buffer {
+// 32 bit samples are usually floats, where subtracting neighbors mixes up sign, exponent and
+// mantissa. XOR with the left neighbor instead zeroes the bits they share, splitting the result
+// into byte planes (most significant first) then groups the mostly zero bytes together.
+fn PackAndFilter32<const kChannels: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+) -> Vec<u8> {
+    let rowSamples = width as usize * kChannels;
+    let mut residuals = vec![0u32; byteCount / 4];
+    debug_assert_eq!(rowSamples * height as usize, residuals.len());
+
+    RowsMut!(residuals, rowSamples)
+        .zip(Rows!(input, rowSamples * 4))
+        .for_each(|(residuals, input)| {
+            let mut prev = [0u32; kChannels];
+
+            for (residuals, input) in residuals
+                .chunks_mut(kChannels)
+                .zip(input.chunks(kChannels * 4))
+            {
+                for i in 0..kChannels {
+                    let a = u32::from_le_bytes([
+                        input[4 * i],
+                        input[4 * i + 1],
+                        input[4 * i + 2],
+                        input[4 * i + 3],
+                    ]);
+                    residuals[i] = a ^ prev[i];
+                    prev[i] = a;
+                }
+            }
+        });
+
+    // Plane split: the most significant byte of every channel first
     let mut output = vec![0; byteCount];
-    const kChannels: usize = 4;
+    let planeBytes = width as usize * height as usize;
+    RowsMut!(output, planeBytes)
+        .enumerate()
+        .for_each(|(plane, output)| {
+            let channel = plane % kChannels;
+            let shift = 24 - 8 * (plane / kChannels);
+            for (x, output) in output.iter_mut().enumerate() {
+                *output = (residuals[x * kChannels + channel] >> shift) as u8;
+            }
+        });
 
-    // Color plane split
+    output
+}
+
+fn UnpackAndUnfilter32<const kChannels: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+) -> ZPNG_Buffer {
+    let mut output = vec![0; byteCount];
     let planeBytes = width as usize * height as usize;
-    let mut input_y_offset = 0;
-    let mut input_u_offset = planeBytes;
-    let mut input_v_offset = planeBytes * 2;
-    let mut input_a_offset = planeBytes * 3;
+    let rowSamples = width as usize * kChannels;
+    debug_assert_eq!(rowSamples * height as usize * 4, output.len());
+
+    RowsMut!(output, rowSamples * 4)
+        .enumerate()
+        .for_each(|(row, output)| {
+            let mut prev = [0u32; kChannels];
+            let offset = row * width as usize;
+
+            for (x, output) in output.chunks_mut(kChannels * 4).enumerate() {
+                for i in 0..kChannels {
+                    let plane =
+                        |byte: usize| input[(byte * kChannels + i) * planeBytes + offset + x];
+                    let residual = u32::from_be_bytes([plane(0), plane(1), plane(2), plane(3)]);
+
+                    let a = residual ^ prev[i];
+                    output[4 * i..4 * i + 4].copy_from_slice(&a.to_le_bytes());
+                    prev[i] = a;
+                }
+            }
+        });
 
-    let mut output_offset = 0;
+    ZPNG_Buffer { Data: output }
+}
 
-    for _row in 0..height {
-        let mut prev = [0; kChannels];
+// YCoCg-R is built from lifting steps, each one only adds a function of the other values, so it is
+// exactly reversible. Unlike GB-RG it has to see the real pixel values rather than the residuals,
+// so the pixels are transformed first and Y, Co, Cg (and alpha) are predicted afterwards. Co and
+// Cg need one bit more than the input, so they get an extra byte plane each. The planes are
+// ordered like the ones of the 32 bit samples, most significant bytes of every channel first.
 
-        for _x in 0..width {
-            let y: u8 = input[input_y_offset];
-            input_y_offset += 1;
+/// Number of byte planes of a channel after YCoCg-R, Co and Cg are wider than the input
+fn YCoCgBytes(channel: usize, bytesPerChannel: usize) -> usize {
+    if channel == 1 || channel == 2 {
+        bytesPerChannel + 1
+    } else {
+        bytesPerChannel
+    }
+}
 
-            let u: u8 = input[input_u_offset];
-            input_u_offset += 1;
+/// Byte planes of the YCoCg-R layout in file order as (channel, shift)
+fn YCoCgPlanes(channels: usize, bytesPerChannel: usize) -> Vec<(usize, u32)> {
+    let mut planes = Vec::new();
+    for byte in (0..=bytesPerChannel).rev() {
+        for channel in 0..channels {
+            if byte < YCoCgBytes(channel, bytesPerChannel) {
+                planes.push((channel, 8 * byte as u32));
+            }
+        }
+    }
+    planes
+}
 
-            let v: u8 = input[input_v_offset];
-            input_v_offset += 1;
+/// Reads a pixel and applies the forward YCoCg-R transform to its first three channels
+fn YCoCgForward<const kChannels: usize, const kBytes: usize>(pixel: &[u8]) -> [i32; kChannels] {
+    let mut values = [0i32; kChannels];
+    for i in 0..kChannels {
+        for byte in 0..kBytes {
+            values[i] |= (pixel[i * kBytes + byte] as i32) << (8 * byte);
+        }
+    }
 
-            let mut a: u8 = input[input_a_offset];
-            input_a_offset += 1;
+    let (r, g, b) = (values[0], values[1], values[2]);
+    let co = r - b;
+    let t = b + (co >> 1);
+    let cg = g - t;
+    values[0] = t + (cg >> 1);
+    values[1] = co;
+    values[2] = cg;
+    values
+}
 
-            // GB-RG filter from BCIF
-            let B: u8 = y;
-            let G: u8 = u.wrapping_add(B);
-            let mut r: u8 = G.wrapping_sub(v);
-            let mut g: u8 = G;
-            let mut b: u8 = B;
+/// ZPNG_ColorFilter::YCoCgR
+fn PackAndFilterYCoCg<const kChannels: usize, const kBytes: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    predictors: &[ZPNG_Predictor],
+) -> Vec<u8> {
+    let rowSamples = width as usize * kChannels;
+    let planeBytes = width as usize * height as usize;
+    let mut residuals = vec![0u32; planeBytes * kChannels];
+    debug_assert_eq!(residuals.len() * kBytes, input.len());
+
+    let rowBytes = rowSamples * kBytes;
+    let zeros = vec![0; rowBytes];
+    let rowAbove = |row: usize| match row {
+        0 => &zeros[..],
+        _ => &input[(row - 1) * rowBytes..row * rowBytes],
+    };
 
-            r = r.wrapping_add(prev[0]);
-            g = g.wrapping_add(prev[1]);
-            b = b.wrapping_add(prev[2]);
-            a = a.wrapping_add(prev[3]);
+    RowsMut!(residuals, rowSamples)
+        .zip(Rows!(input, rowBytes))
+        .enumerate()
+        .for_each(|(row, (residuals, input))| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let mut left = [0i32; kChannels];
+            let mut upLeft = [0i32; kChannels];
+
+            // the row above is transformed again, which is cheaper than keeping it around
+            for ((residuals, input), up) in residuals
+                .chunks_mut(kChannels)
+                .zip(input.chunks(kChannels * kBytes))
+                .zip(rowAbove(row).chunks(kChannels * kBytes))
+            {
+                let values = YCoCgForward::<kChannels, kBytes>(input);
+                let above = YCoCgForward::<kChannels, kBytes>(up);
+
+                for i in 0..kChannels {
+                    let p = Predict(predictor, left[i], above[i], upLeft[i]);
+                    residuals[i] = values[i].wrapping_sub(p) as u32;
+                    left[i] = values[i];
+                    upLeft[i] = above[i];
+                }
+            }
+        });
+
+    let planes = YCoCgPlanes(kChannels, kBytes);
+    let mut output = vec![0; planeBytes * planes.len()];
+    RowsMut!(output, planeBytes)
+        .enumerate()
+        .for_each(|(plane, output)| {
+            let (channel, shift) = planes[plane];
+            for (x, output) in output.iter_mut().enumerate() {
+                *output = (residuals[x * kChannels + channel] >> shift) as u8;
+            }
+        });
 
-            output[output_offset + 0] = r;
-            output[output_offset + 1] = g;
-            output[output_offset + 2] = b;
-            output[output_offset + 3] = a;
+    output
+}
 
-            prev[0] = r;
-            prev[1] = g;
-            prev[2] = b;
-            prev[3] = a;
+/// ZPNG_ColorFilter::YCoCgR
+fn UnpackAndUnfilterYCoCg<const kChannels: usize, const kBytes: usize>(
+    input: &[u8],
+    width: u32,
+    height: u32,
+    byteCount: usize,
+    predictors: &[ZPNG_Predictor],
+) -> ZPNG_Buffer {
+    let mut output = vec![0; byteCount];
+    let planeBytes = width as usize * height as usize;
+    let rowSamples = width as usize * kChannels;
+    let planes = YCoCgPlanes(kChannels, kBytes);
+    debug_assert_eq!(rowSamples * height as usize * kBytes, output.len());
+    debug_assert_eq!(planeBytes * planes.len(), input.len());
+
+    UnfilterRows(
+        &mut output,
+        rowSamples * kBytes,
+        predictors,
+        |row, output, up| {
+            let predictor = predictors[row / kPredictorBandRows];
+            let mut left = [0i32; kChannels];
+            let mut upLeft = [0i32; kChannels];
+            let offset = row * width as usize;
+
+            for (x, (output, up)) in output
+                .chunks_mut(kChannels * kBytes)
+                .zip(up.chunks(kChannels * kBytes))
+                .enumerate()
+            {
+                let mut residuals = [0u32; kChannels];
+                for (plane, &(channel, shift)) in planes.iter().enumerate() {
+                    residuals[channel] |= (input[plane * planeBytes + offset + x] as u32) << shift;
+                }
+
+                // only the stored bits are kept, which makes Co and Cg signed again
+                let above = YCoCgForward::<kChannels, kBytes>(up);
+                let mut values = [0i32; kChannels];
+                for i in 0..kChannels {
+                    let p = Predict(predictor, left[i], above[i], upLeft[i]);
+                    let unused = 32 - 8 * YCoCgBytes(i, kBytes) as u32;
+                    let a = (p as u32).wrapping_add(residuals[i]) << unused;
+                    values[i] = if i == 1 || i == 2 {
+                        a as i32 >> unused
+                    } else {
+                        (a >> unused) as i32
+                    };
+                    left[i] = values[i];
+                    upLeft[i] = above[i];
+                }
+
+                let (y, co, cg) = (values[0], values[1], values[2]);
+                let t = y - (cg >> 1);
+                let g = cg + t;
+                let b = t - (co >> 1);
+                values[0] = b + co;
+                values[1] = g;
+                values[2] = b;
+
+                for i in 0..kChannels {
+                    output[i * kBytes..(i + 1) * kBytes]
+                        .copy_from_slice(&values[i].to_le_bytes()[..kBytes]);
+                }
+            }
+        },
+    );
 
-            output_offset += kChannels;
-        }
-    }
-    debug_assert_eq!(output.len(), output_offset);
     ZPNG_Buffer { Data: output }
 }
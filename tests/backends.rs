@@ -0,0 +1,165 @@
+//! The entropy backends besides zstd, each only built with its feature
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::Image;
+use zpng_rs_lib::*;
+
+fn Compress(backend: EntropyBackend) -> Result<ZPNG_Buffer, ZpngError> {
+    let options = CompressOptions {
+        EntropyBackend: backend,
+        ..CompressOptions::default()
+    };
+    ZPNG_CompressWithOptions(&Image(16, 9, 3, 1), &options)
+}
+
+/// A large uniform image at the highest level shrinks the most, the default limits have to
+/// accept it
+#[cfg(any(feature = "lz4", feature = "deflate", feature = "brotli"))]
+fn Uniform(backend: EntropyBackend) {
+    let image = ZPNG_ImageData {
+        Buffer: ZPNG_Buffer {
+            Data: vec![0x80; 3000 * 3000 * 3],
+        },
+        BytesPerChannel: 1,
+        Channels: 3,
+        WidthPixels: 3000,
+        HeightPixels: 3000,
+    };
+    // deflate and brotli clamp it to their highest level
+    let options = CompressOptions {
+        EntropyBackend: backend,
+        Level: 22,
+        ..CompressOptions::default()
+    };
+    let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+}
+
+#[cfg(any(feature = "lz4", feature = "deflate", feature = "brotli"))]
+fn Backend(backend: EntropyBackend) {
+    let image = Image(16, 9, 3, 1);
+    let compressed = Compress(backend).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC_V2);
+    assert_eq!(header.EntropyBackend, backend);
+
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert_eq!(decompressed.Buffer.Data, image.Buffer.Data);
+
+    // the payload starts with the size of the filtered data
+    let overhead = header.OverheadBytes();
+    let packedBytes = header.PackedBytes();
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&compressed.Data[overhead..overhead + 8]);
+    assert_eq!(u64::from_le_bytes(prefix), packedBytes);
+
+    let mut wrongSize = ZPNG_Buffer {
+        Data: compressed.Data.clone(),
+    };
+    wrongSize.Data[overhead..overhead + 8].copy_from_slice(&(packedBytes + 1).to_le_bytes());
+    match ZPNG_Decompress(&wrongSize) {
+        Err(ZpngError::SizeMismatch { Expected, Actual }) => {
+            assert_eq!((Expected, Actual), (packedBytes, packedBytes + 1))
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    let noPrefix = ZPNG_Buffer {
+        Data: compressed.Data[..overhead + 4].to_vec(),
+    };
+    match ZPNG_Decompress(&noPrefix) {
+        Err(ZpngError::Backend(got, err)) => {
+            assert_eq!(got, backend);
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    let truncated = ZPNG_Buffer {
+        Data: compressed.Data[..compressed.Data.len() - 4].to_vec(),
+    };
+    match ZPNG_Decompress(&truncated) {
+        Err(ZpngError::Backend(got, _)) => assert_eq!(got, backend),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+/// Both directions report the backend this build was made without
+// unused when every backend is enabled
+#[allow(dead_code)]
+fn Disabled(backend: EntropyBackend) {
+    match Compress(backend) {
+        Err(ZpngError::BackendDisabled(got)) => assert_eq!(got, backend),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    // a 4×1 grayscale image, recorded as 4 bytes behind the size prefix
+    let mut data = ZPNG_HEADER_MAGIC_V2.to_le_bytes().to_vec();
+    data.extend_from_slice(&[1, 1]);
+    data.extend_from_slice(&4u32.to_le_bytes());
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&[0, 0, 0, backend as u8]);
+    data.extend_from_slice(&4u64.to_le_bytes());
+    data.extend_from_slice(&[1, 2, 3, 4]);
+    match ZPNG_Decompress(&ZPNG_Buffer { Data: data }) {
+        Err(ZpngError::BackendDisabled(got)) => assert_eq!(got, backend),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4() {
+    Backend(EntropyBackend::Lz4);
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4_uniform() {
+    Uniform(EntropyBackend::Lz4);
+}
+
+#[cfg(not(feature = "lz4"))]
+#[test]
+fn lz4_disabled() {
+    Disabled(EntropyBackend::Lz4);
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn deflate() {
+    Backend(EntropyBackend::Deflate);
+}
+
+#[cfg(feature = "deflate")]
+#[test]
+fn deflate_uniform() {
+    Uniform(EntropyBackend::Deflate);
+}
+
+#[cfg(not(feature = "deflate"))]
+#[test]
+fn deflate_disabled() {
+    Disabled(EntropyBackend::Deflate);
+}
+
+#[cfg(feature = "brotli")]
+#[test]
+fn brotli() {
+    Backend(EntropyBackend::Brotli);
+}
+
+#[cfg(feature = "brotli")]
+#[test]
+fn brotli_uniform() {
+    Uniform(EntropyBackend::Brotli);
+}
+
+#[cfg(not(feature = "brotli"))]
+#[test]
+fn brotli_disabled() {
+    Disabled(EntropyBackend::Brotli);
+}
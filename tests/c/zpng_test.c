@@ -0,0 +1,104 @@
+/*
+    Round trips images through the C API, the same calls the reference Zpng takes.
+
+    make -C tests/c
+*/
+
+#include "zpng.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static int failures = 0;
+
+#define CHECK(condition)                                                  \
+    do {                                                                  \
+        if (!(condition)) {                                               \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #condition); \
+            ++failures;                                                   \
+        }                                                                 \
+    } while (0)
+
+static void RoundTrip(unsigned width, unsigned height, unsigned channels,
+                      unsigned bytesPerChannel, unsigned padding)
+{
+    unsigned rowBytes = width * channels * bytesPerChannel;
+    unsigned stride = rowBytes + padding;
+    unsigned char* pixels = (unsigned char*)malloc(stride * height + 1);
+
+    for (unsigned y = 0; y < height; ++y) {
+        for (unsigned x = 0; x < stride; ++x) {
+            /* padding is filled with garbage that must not end up in the file */
+            pixels[y * stride + x] = x < rowBytes ? (unsigned char)(x * 3 + y * 7 + (x * y) % 5) : 0xAA;
+        }
+    }
+
+    ZPNG_ImageData image;
+    image.Buffer.Data = pixels;
+    image.Buffer.Bytes = stride * height;
+    image.BytesPerChannel = bytesPerChannel;
+    image.Channels = channels;
+    image.WidthPixels = width;
+    image.HeightPixels = height;
+    image.StrideBytes = stride;
+
+    ZPNG_Buffer compressed = ZPNG_Compress(&image);
+    CHECK(compressed.Data != NULL);
+    if (compressed.Data == NULL) {
+        free(pixels);
+        return;
+    }
+
+    ZPNG_ImageData decompressed = ZPNG_Decompress(compressed);
+    CHECK(decompressed.Buffer.Data != NULL);
+    CHECK(decompressed.WidthPixels == width);
+    CHECK(decompressed.HeightPixels == height);
+    CHECK(decompressed.Channels == channels);
+    CHECK(decompressed.BytesPerChannel == bytesPerChannel);
+    CHECK(decompressed.StrideBytes == rowBytes);
+    CHECK(decompressed.Buffer.Bytes == rowBytes * height);
+    if (decompressed.Buffer.Data != NULL) {
+        for (unsigned y = 0; y < height; ++y) {
+            CHECK(memcmp(decompressed.Buffer.Data + y * rowBytes, pixels + y * stride, rowBytes) == 0);
+        }
+    }
+
+    printf("%ux%u, %u channels of %u bytes: %u -> %u bytes\n", width, height, channels,
+           bytesPerChannel, rowBytes * height, compressed.Bytes);
+
+    ZPNG_Free(&compressed);
+    CHECK(compressed.Data == NULL && compressed.Bytes == 0);
+    ZPNG_Free(&decompressed.Buffer);
+    CHECK(decompressed.Buffer.Data == NULL);
+    free(pixels);
+}
+
+int main(void)
+{
+    RoundTrip(64, 48, 4, 1, 0);
+    RoundTrip(101, 37, 3, 1, 13);
+    RoundTrip(33, 20, 1, 2, 2);
+    RoundTrip(17, 9, 4, 4, 0);
+    RoundTrip(70000, 2, 1, 1, 0);
+
+    /* garbage and bad parameters fail with a null pointer */
+    unsigned char garbage[32] = { 0xF8, 0xFB, 4, 1, 16, 0, 16, 0 };
+    ZPNG_Buffer corrupt = { garbage, sizeof(garbage) };
+    ZPNG_ImageData failed = ZPNG_Decompress(corrupt);
+    CHECK(failed.Buffer.Data == NULL);
+    ZPNG_Free(&failed.Buffer);
+
+    ZPNG_ImageData unsupported = { { garbage, sizeof(garbage) }, 1, 0, 2, 2, 0 };
+    ZPNG_Buffer nothing = ZPNG_Compress(&unsupported);
+    CHECK(nothing.Data == NULL);
+    CHECK(ZPNG_Compress(NULL).Data == NULL);
+    ZPNG_Free(NULL);
+
+    if (failures != 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,43 @@
+//! Test images shared by the integration tests
+#![allow(dead_code, non_snake_case)]
+
+use zpng_rs_lib::*;
+
+/// Image whose bytes count up by 7 modulo 251, so neighboring bytes always differ
+pub fn Image(width: u32, height: u32, channels: u8, bytesPerChannel: u8) -> ZPNG_ImageData {
+    let bytes = width as usize * height as usize * channels as usize * bytesPerChannel as usize;
+    ZPNG_ImageData {
+        Buffer: ZPNG_Buffer {
+            Data: (0..bytes).map(|i| (i * 7 % 251) as u8).collect(),
+        },
+        BytesPerChannel: bytesPerChannel,
+        Channels: channels,
+        WidthPixels: width,
+        HeightPixels: height,
+    }
+}
+
+/// Deterministic image with smooth areas, edges and some noise, closer to a photo than `Image`
+pub fn TestImage(width: u32, height: u32, channels: u8, bytesPerChannel: u8) -> ZPNG_ImageData {
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut data = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            for c in 0..channels as u32 * bytesPerChannel as u32 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let edge = if (x / 32 + y / 16) % 2 == 0 { 0x40 } else { 0 };
+                data.push((x + 3 * y + 11 * c + edge + (state % 4) as u32) as u8);
+            }
+        }
+    }
+
+    ZPNG_ImageData {
+        Buffer: ZPNG_Buffer { Data: data },
+        BytesPerChannel: bytesPerChannel,
+        Channels: channels,
+        WidthPixels: width,
+        HeightPixels: height,
+    }
+}
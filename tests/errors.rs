@@ -0,0 +1,109 @@
+//! `ZPNG_Compress` and `ZPNG_Decompress` report what went wrong as a `ZpngError`.
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::Image;
+use std::error::Error;
+use zpng_rs_lib::*;
+
+#[test]
+fn round_trip() {
+    let image = Image(13, 7, 3, 1);
+    let compressed = ZPNG_Compress(&image).unwrap();
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert_eq!(decompressed.Buffer.Data, image.Buffer.Data);
+    assert_eq!(decompressed.WidthPixels, 13);
+    assert_eq!(decompressed.HeightPixels, 7);
+    assert_eq!(decompressed.Channels, 3);
+    assert_eq!(decompressed.BytesPerChannel, 1);
+}
+
+#[test]
+fn unsupported_format() {
+    for &(channels, bytesPerChannel) in &[(0, 1), (1, 0), (9, 1), (5, 4), (3, 3)] {
+        let result = ZPNG_Compress(&Image(4, 4, channels, bytesPerChannel));
+        match result {
+            Err(ZpngError::UnsupportedFormat {
+                Channels,
+                BytesPerChannel,
+            }) => {
+                assert_eq!((Channels, BytesPerChannel), (channels, bytesPerChannel));
+            }
+            other => panic!("{}x{}: {:?}", channels, bytesPerChannel, other),
+        }
+    }
+}
+
+#[test]
+fn buffer_of_the_wrong_size() {
+    let mut image = Image(4, 4, 3, 1);
+    image.Buffer.Data.pop();
+    match ZPNG_Compress(&image) {
+        Err(ZpngError::SizeMismatch { Expected, Actual }) => {
+            assert_eq!((Expected, Actual), (48, 47));
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn bad_magic() {
+    let mut compressed = ZPNG_Compress(&Image(4, 4, 1, 1)).unwrap();
+    compressed.Data[0] = 0x12;
+    compressed.Data[1] = 0x34;
+    match ZPNG_Decompress(&compressed) {
+        Err(ZpngError::BadMagic(0x3412)) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn truncated_header() {
+    let compressed = ZPNG_Compress(&Image(4, 4, 1, 1)).unwrap();
+    for len in 0..8 {
+        let truncated = ZPNG_Buffer {
+            Data: compressed.Data[..len].to_vec(),
+        };
+        match ZPNG_Decompress(&truncated) {
+            Err(ZpngError::TruncatedHeader(got)) => assert_eq!(got, len),
+            other => panic!("{} bytes: {:?}", len, other),
+        }
+    }
+}
+
+#[test]
+fn corrupt_payload() {
+    let mut compressed = ZPNG_Compress(&Image(64, 64, 4, 1)).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    // keep the zstd magic and frame header, break the block behind it
+    let start = header.OverheadBytes() + 12;
+    for byte in &mut compressed.Data[start..] {
+        *byte = 0xFF;
+    }
+
+    let err = ZPNG_Decompress(&compressed).unwrap_err();
+    assert!(matches!(err, ZpngError::Zstd(_)), "{:?}", err);
+    assert!(err.source().is_some());
+    assert!(err.to_string().starts_with("zstd error"), "{}", err);
+}
+
+#[test]
+fn messages() {
+    let err = ZpngError::UnsupportedFormat {
+        Channels: 9,
+        BytesPerChannel: 1,
+    };
+    assert_eq!(
+        err.to_string(),
+        "unsupported format with 9 channels and 1 bytes per channel"
+    );
+    assert!(err.source().is_none());
+
+    let err = ZpngError::BadMagic(0x3412);
+    assert_eq!(
+        err.to_string(),
+        "bad magic 0x3412, expected 0xfbf8 or 0xfbf9"
+    );
+}
@@ -0,0 +1,125 @@
+//! Custom filters registered with `ZPNG_RegisterFilter`
+//!
+//! The registry is global, so every test uses its own filter IDs.
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::Image;
+use zpng_rs_lib::*;
+
+/// XORs every byte with a key, which is its own inverse
+struct XorFilter {
+    id: u16,
+    key: u8,
+}
+
+impl Filter for XorFilter {
+    fn Id(&self) -> u16 {
+        self.id
+    }
+
+    fn Forward(&self, image: &ZPNG_ImageData) -> Vec<u8> {
+        image.Buffer.Data.iter().map(|b| b ^ self.key).collect()
+    }
+
+    fn Inverse(&self, _header: &ZPNG_Header, packed: &[u8]) -> Result<Vec<u8>, ZpngError> {
+        Ok(packed.iter().map(|b| b ^ self.key).collect())
+    }
+}
+
+/// Loses the last byte on the way back
+struct ShortFilter {
+    id: u16,
+}
+
+impl Filter for ShortFilter {
+    fn Id(&self) -> u16 {
+        self.id
+    }
+
+    fn Forward(&self, image: &ZPNG_ImageData) -> Vec<u8> {
+        image.Buffer.Data.clone()
+    }
+
+    fn Inverse(&self, _header: &ZPNG_Header, packed: &[u8]) -> Result<Vec<u8>, ZpngError> {
+        Ok(packed[..packed.len() - 1].to_vec())
+    }
+}
+
+fn Compress(image: &ZPNG_ImageData, id: u16) -> Result<ZPNG_Buffer, ZpngError> {
+    let options = CompressOptions {
+        CustomFilter: Some(id),
+        ..CompressOptions::default()
+    };
+    ZPNG_CompressWithOptions(image, &options)
+}
+
+#[test]
+fn round_trip() {
+    ZPNG_RegisterFilter(Box::new(XorFilter {
+        id: 0x4001,
+        key: 0x5A,
+    }))
+    .unwrap();
+
+    let image = Image(13, 7, 3, 1);
+    let compressed = Compress(&image, 0x4001).unwrap();
+
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC_V2);
+    assert_eq!(header.Flags, ZPNG_FLAG_CUSTOM_FILTER);
+    assert_eq!(header.CustomFilter, Some(0x4001));
+    assert_eq!(&compressed.Data[13..15], &0x4001u16.to_le_bytes());
+
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert_eq!(decompressed.WidthPixels, image.WidthPixels);
+    assert_eq!(decompressed.HeightPixels, image.HeightPixels);
+    assert_eq!(decompressed.Channels, image.Channels);
+    assert_eq!(decompressed.Buffer.Data, image.Buffer.Data);
+}
+
+#[test]
+fn already_registered() {
+    ZPNG_RegisterFilter(Box::new(XorFilter { id: 0x4002, key: 1 })).unwrap();
+    match ZPNG_RegisterFilter(Box::new(XorFilter { id: 0x4002, key: 2 })) {
+        Err(ZpngError::FilterAlreadyRegistered(0x4002)) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn unknown_filter() {
+    match Compress(&Image(13, 7, 3, 1), 0x4003) {
+        Err(ZpngError::UnknownFilter(0x4003)) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    // a file written with a filter this build doesn't have
+    ZPNG_RegisterFilter(Box::new(XorFilter { id: 0x4004, key: 3 })).unwrap();
+    let mut compressed = Compress(&Image(13, 7, 3, 1), 0x4004).unwrap();
+    compressed.Data[13..15].copy_from_slice(&0x4005u16.to_le_bytes());
+    assert_eq!(
+        ZPNG_ReadHeader(&compressed.Data).unwrap().CustomFilter,
+        Some(0x4005)
+    );
+    match ZPNG_Decompress(&compressed) {
+        Err(ZpngError::UnknownFilter(0x4005)) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn inverse_of_the_wrong_size() {
+    ZPNG_RegisterFilter(Box::new(ShortFilter { id: 0x4006 })).unwrap();
+    let image = Image(13, 7, 3, 1);
+    let compressed = Compress(&image, 0x4006).unwrap();
+    let expected = image.Buffer.Data.len() as u64;
+    match ZPNG_Decompress(&compressed) {
+        Err(ZpngError::SizeMismatch { Expected, Actual }) => {
+            assert_eq!((Expected, Actual), (expected, expected - 1))
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
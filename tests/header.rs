@@ -0,0 +1,266 @@
+//! `ZPNG_ReadHeader` only looks at the header, and the size helpers of `ZPNG_Header` say what
+//! decompressing will need.
+#![allow(non_snake_case)]
+
+mod common;
+
+#[cfg(feature = "zstd-safe")]
+use common::Image;
+use zpng_rs_lib::*;
+
+/// v1 header as the reference Zpng writes it
+fn V1Header(width: u16, height: u16, channels: u8, bytesPerChannel: u8) -> Vec<u8> {
+    let mut data = ZPNG_HEADER_MAGIC.to_le_bytes().to_vec();
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    data.push(channels);
+    data.push(bytesPerChannel);
+    data
+}
+
+#[test]
+fn v1_fields() {
+    let header = ZPNG_ReadHeader(&V1Header(640, 480, 3, 1)).unwrap();
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC);
+    assert_eq!((header.Width, header.Height), (640, 480));
+    assert_eq!((header.Channels, header.BytesPerChannel), (3, 1));
+    assert_eq!(header.Flags, 0);
+    assert_eq!(header.Predictor, ZPNG_Predictor::Left);
+    assert_eq!(header.CustomFilter, None);
+    assert_eq!(header.EntropyBackend, EntropyBackend::Zstd);
+    assert_eq!(header.OverheadBytes(), ZPNG_HEADER_OVERHEAD_BYTES as usize);
+
+    // the reference filters 3 and 4 byte pixels with GB-RG and everything else not at all
+    assert_eq!(header.ColorFilter, ZPNG_ColorFilter::GbRg);
+    let header = ZPNG_ReadHeader(&V1Header(640, 480, 2, 2)).unwrap();
+    assert_eq!(header.ColorFilter, ZPNG_ColorFilter::GbRg);
+    let header = ZPNG_ReadHeader(&V1Header(640, 480, 3, 2)).unwrap();
+    assert_eq!(header.ColorFilter, ZPNG_ColorFilter::None);
+}
+
+#[test]
+fn only_the_header_is_read() {
+    let mut data = V1Header(2, 2, 1, 1);
+    data.extend_from_slice(b"whatever follows is not looked at");
+    assert!(ZPNG_ReadHeader(&data).is_ok());
+}
+
+#[test]
+fn v1_errors() {
+    let header = V1Header(16, 16, 4, 1);
+    for len in 0..header.len() {
+        match ZPNG_ReadHeader(&header[..len]) {
+            Err(ZpngError::TruncatedHeader(got)) => assert_eq!(got, len),
+            other => panic!("{} bytes: {:?}", len, other),
+        }
+    }
+
+    match ZPNG_ReadHeader(&[0xFF, 0xFF, 0, 0, 0, 0, 0, 0]) {
+        Err(ZpngError::BadMagic(0xFFFF)) => {}
+        other => panic!("{:?}", other),
+    }
+
+    match ZPNG_ReadHeader(&V1Header(16, 16, 0, 1)) {
+        Err(ZpngError::UnsupportedFormat {
+            Channels: 0,
+            BytesPerChannel: 1,
+        }) => {}
+        other => panic!("{:?}", other),
+    }
+    match ZPNG_ReadHeader(&V1Header(16, 16, 9, 1)) {
+        Err(ZpngError::UnsupportedFormat {
+            Channels: 9,
+            BytesPerChannel: 1,
+        }) => {}
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn sizes() {
+    let header = ZPNG_ReadHeader(&V1Header(100, 50, 3, 1)).unwrap();
+    assert_eq!(header.PixelCount(), 5000);
+    assert_eq!(header.DecodedBytes(), 15000);
+    assert_eq!(header.PackedBytes(), 15000);
+    // packing buffer and output
+    assert_eq!(header.ExpectedMemory(), 30000);
+
+    let header = ZPNG_ReadHeader(&V1Header(100, 50, 4, 2)).unwrap();
+    assert_eq!(header.DecodedBytes(), 40000);
+    assert_eq!(header.ExpectedMemory(), 80000);
+
+    // the largest v1 image doesn't overflow anything
+    let header = ZPNG_ReadHeader(&V1Header(u16::MAX, u16::MAX, 4, 2)).unwrap();
+    let pixels = u16::MAX as u64 * u16::MAX as u64;
+    assert_eq!(header.PixelCount(), pixels);
+    assert_eq!(header.DecodedBytes(), pixels * 8);
+    assert_eq!(header.ExpectedMemory(), pixels * 16);
+
+    let header = ZPNG_ReadHeader(&V1Header(0, 10, 1, 1)).unwrap();
+    assert_eq!(header.DecodedBytes(), 0);
+}
+
+#[cfg(feature = "zstd-safe")]
+#[test]
+fn header_of_compressed_image() {
+    let image = ZPNG_ImageData {
+        Buffer: ZPNG_Buffer {
+            Data: vec![7; 33 * 20 * 4],
+        },
+        BytesPerChannel: 1,
+        Channels: 4,
+        WidthPixels: 33,
+        HeightPixels: 20,
+    };
+    // the reference always filters RGBA with GB-RG, builds without ENABLE_RGB_COLOR_FILTER don't
+    let options = CompressOptions {
+        ColorFilter: ZPNG_ColorFilter::GbRg,
+        ..CompressOptions::default()
+    };
+    let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC);
+    assert_eq!((header.Width, header.Height), (33, 20));
+    assert_eq!((header.Channels, header.BytesPerChannel), (4, 1));
+    assert_eq!(header.DecodedBytes(), image.Buffer.Data.len() as u64);
+    assert_eq!(&compressed.Data[..8], &V1Header(33, 20, 4, 1)[..]);
+}
+
+#[cfg(feature = "zstd-safe")]
+#[test]
+fn rgb_without_color_filter() {
+    // the reference would filter RGB with GB-RG, so leaving it out needs the v2 header
+    let image = Image(33, 20, 3, 1);
+    let options = CompressOptions {
+        ColorFilter: ZPNG_ColorFilter::None,
+        ..CompressOptions::default()
+    };
+    let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC_V2);
+    assert_eq!(header.ColorFilter, ZPNG_ColorFilter::None);
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+}
+
+#[cfg(feature = "zstd-safe")]
+#[test]
+fn v1_rgb_is_gbrg() {
+    // filtered like the reference does it: left deltas, then B, G - B and G - R planes
+    let image = Image(33, 20, 3, 1);
+    let planeBytes = 33 * 20;
+    let mut packing = vec![0u8; planeBytes * 3];
+    for (i, pixel) in image.Buffer.Data.chunks(3).enumerate() {
+        let left = match i % 33 {
+            0 => [0; 3],
+            _ => [
+                image.Buffer.Data[i * 3 - 3],
+                image.Buffer.Data[i * 3 - 2],
+                image.Buffer.Data[i * 3 - 1],
+            ],
+        };
+        let r = pixel[0].wrapping_sub(left[0]);
+        let g = pixel[1].wrapping_sub(left[1]);
+        let b = pixel[2].wrapping_sub(left[2]);
+        packing[i] = b;
+        packing[planeBytes + i] = g.wrapping_sub(b);
+        packing[planeBytes * 2 + i] = g.wrapping_sub(r);
+    }
+    let mut frame = vec![0; zstd_safe::compress_bound(packing.len())];
+    let size = zstd_safe::compress(&mut frame, &packing, 1).unwrap();
+
+    let mut data = V1Header(33, 20, 3, 1);
+    data.extend_from_slice(&frame[..size]);
+    let decompressed = ZPNG_Decompress(&ZPNG_Buffer { Data: data }).unwrap();
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+}
+
+/// v2 header with no flags, no color filter, the left predictor and zstd
+fn V2Header(width: u32, height: u32, channels: u8, bytesPerChannel: u8) -> Vec<u8> {
+    let mut data = ZPNG_HEADER_MAGIC_V2.to_le_bytes().to_vec();
+    data.push(channels);
+    data.push(bytesPerChannel);
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data
+}
+
+#[test]
+fn v2_fields() {
+    let header = ZPNG_ReadHeader(&V2Header(70000, 3, 3, 1)).unwrap();
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC_V2);
+    assert_eq!((header.Width, header.Height), (70000, 3));
+    assert_eq!(header.ColorFilter, ZPNG_ColorFilter::None);
+    assert_eq!(
+        header.OverheadBytes(),
+        ZPNG_HEADER_V2_OVERHEAD_BYTES as usize
+    );
+
+    let header = ZPNG_ReadHeader(&V2Header(u32::MAX, u32::MAX, 4, 4)).unwrap();
+    let pixels = u32::MAX as u64 * u32::MAX as u64;
+    assert_eq!(header.PixelCount(), pixels);
+    assert_eq!(header.DecodedBytes(), pixels.saturating_mul(16));
+}
+
+#[test]
+fn v2_errors() {
+    let header = V2Header(16, 16, 4, 1);
+    for len in 2..header.len() {
+        match ZPNG_ReadHeader(&header[..len]) {
+            Err(ZpngError::TruncatedHeader(got)) => assert_eq!(got, len),
+            other => panic!("{} bytes: {:?}", len, other),
+        }
+    }
+
+    let cases = [
+        (12, 0x80, "flags"),
+        // the 16 bit flag on 8 bit samples
+        (12, ZPNG_FLAG_16BIT_SAMPLES, "flags"),
+        (13, 9, "color filter"),
+        (14, 200, "predictor"),
+        (15, 4, "entropy backend"),
+    ];
+    for &(offset, value, field) in cases.iter() {
+        let mut crafted = header.clone();
+        crafted[offset] = value;
+        match ZPNG_ReadHeader(&crafted) {
+            Err(ZpngError::UnknownHeaderValue { Field, Value }) => {
+                assert_eq!((Field, Value), (field, value as u32))
+            }
+            other => panic!("byte {} = {}: {:?}", offset, value, other),
+        }
+    }
+}
+
+#[cfg(feature = "zstd-safe")]
+fn RoundTripL8(width: u32, height: u32) -> ZPNG_Header {
+    let image = ZPNG_ImageData {
+        Buffer: ZPNG_Buffer {
+            Data: (0..width * height).map(|i| (i % 253) as u8).collect(),
+        },
+        BytesPerChannel: 1,
+        Channels: 1,
+        WidthPixels: width,
+        HeightPixels: height,
+    };
+    let compressed = ZPNG_Compress(&image).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    assert_eq!((header.Width, header.Height), (width, height));
+
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert_eq!(decompressed.WidthPixels, width);
+    assert_eq!(decompressed.HeightPixels, height);
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+    header
+}
+
+#[cfg(feature = "zstd-safe")]
+#[test]
+fn wide_and_tall_images() {
+    assert_eq!(RoundTripL8(70000, 2).Magic, ZPNG_HEADER_MAGIC_V2);
+    assert_eq!(RoundTripL8(2, 70000).Magic, ZPNG_HEADER_MAGIC_V2);
+    // the reference can still read the largest image that fits its header
+    assert_eq!(RoundTripL8(65535, 2).Magic, ZPNG_HEADER_MAGIC);
+    assert_eq!(RoundTripL8(65536, 2).Magic, ZPNG_HEADER_MAGIC_V2);
+}
@@ -0,0 +1,172 @@
+//! `ZpngDecoder` and `ZpngEncoder` with the `image` crate
+#![cfg(all(feature = "image", feature = "zstd-safe"))]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::Image;
+use image::error::{ParameterErrorKind, UnsupportedErrorKind};
+use image::{
+    ColorType, DynamicImage, ExtendedColorType, GenericImageView, ImageBuffer, ImageEncoder,
+    ImageError,
+};
+use std::io::Cursor;
+use zpng_rs_lib::*;
+
+const WIDTH: u32 = 11;
+const HEIGHT: u32 = 6;
+
+fn Bytes(channels: u8) -> Vec<u8> {
+    Image(WIDTH, HEIGHT, channels, 1).Buffer.Data
+}
+
+fn Words(channels: u32) -> Vec<u16> {
+    (0..WIDTH * HEIGHT * channels)
+        .map(|i| (i * 7919 % 65521) as u16)
+        .collect()
+}
+
+fn Sample(color: ColorType) -> DynamicImage {
+    match color {
+        ColorType::L8 => {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(WIDTH, HEIGHT, Bytes(1)).unwrap())
+        }
+        ColorType::La8 => {
+            DynamicImage::ImageLumaA8(ImageBuffer::from_raw(WIDTH, HEIGHT, Bytes(2)).unwrap())
+        }
+        ColorType::Rgb8 => {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(WIDTH, HEIGHT, Bytes(3)).unwrap())
+        }
+        ColorType::Rgba8 => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(WIDTH, HEIGHT, Bytes(4)).unwrap())
+        }
+        ColorType::L16 => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(WIDTH, HEIGHT, Words(1)).unwrap())
+        }
+        ColorType::La16 => {
+            DynamicImage::ImageLumaA16(ImageBuffer::from_raw(WIDTH, HEIGHT, Words(2)).unwrap())
+        }
+        ColorType::Rgb16 => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(WIDTH, HEIGHT, Words(3)).unwrap())
+        }
+        ColorType::Rgba16 => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(WIDTH, HEIGHT, Words(4)).unwrap())
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn Encode(image: &DynamicImage, options: CompressOptions) -> Vec<u8> {
+    let mut output = Vec::new();
+    ZpngEncoder::with_options(&mut output, options)
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color(),
+        )
+        .unwrap();
+    output
+}
+
+fn Decode(data: &[u8]) -> DynamicImage {
+    let decoder = match ZpngDecoder::new(Cursor::new(data)) {
+        Ok(decoder) => decoder,
+        Err(err) => panic!("{}", err),
+    };
+    DynamicImage::from_decoder(decoder).unwrap()
+}
+
+const COLORS: [ColorType; 8] = [
+    ColorType::L8,
+    ColorType::La8,
+    ColorType::Rgb8,
+    ColorType::Rgba8,
+    ColorType::L16,
+    ColorType::La16,
+    ColorType::Rgb16,
+    ColorType::Rgba16,
+];
+
+#[test]
+fn round_trip() {
+    for &color in COLORS.iter() {
+        let image = Sample(color);
+        let compressed = Encode(&image, CompressOptions::default());
+        let header = ZPNG_ReadHeader(&compressed).unwrap();
+        assert_eq!(header.Channels, color.channel_count(), "{:?}", color);
+
+        let decoded = Decode(&compressed);
+        assert_eq!(decoded.color(), color);
+        assert_eq!(decoded.dimensions(), (WIDTH, HEIGHT));
+        assert!(decoded.as_bytes() == image.as_bytes(), "{:?}", color);
+    }
+}
+
+#[test]
+fn round_trip_with_options() {
+    let options = CompressOptions {
+        ColorFilter: ZPNG_ColorFilter::YCoCgR,
+        Predictor: ZPNG_Predictor::Adaptive,
+        Filter16Bit: true,
+        ..CompressOptions::default()
+    };
+    for &color in COLORS.iter() {
+        let image = Sample(color);
+        let decoded = Decode(&Encode(&image, options));
+        assert_eq!(decoded.color(), color);
+        assert!(decoded.as_bytes() == image.as_bytes(), "{:?}", color);
+    }
+}
+
+#[test]
+fn buffer_of_the_wrong_size() {
+    let image = Sample(ColorType::Rgb8);
+    let bytes = image.as_bytes();
+    let mut output = Vec::new();
+    match ZpngEncoder::new(&mut output).write_image(
+        &bytes[..bytes.len() - 1],
+        WIDTH,
+        HEIGHT,
+        ColorType::Rgb8,
+    ) {
+        Err(ImageError::Parameter(err)) => {
+            assert_eq!(err.kind(), ParameterErrorKind::DimensionMismatch)
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(output.is_empty());
+}
+
+#[test]
+fn unsupported_color() {
+    // BGR would come back as RGB
+    let mut output = Vec::new();
+    match ZpngEncoder::new(&mut output).write_image(&Bytes(3), WIDTH, HEIGHT, ColorType::Bgr8) {
+        Err(ImageError::Unsupported(err)) => assert_eq!(
+            err.kind(),
+            UnsupportedErrorKind::Color(ExtendedColorType::Bgr8)
+        ),
+        other => panic!("{:?}", other),
+    }
+
+    // zpng takes 32 bit samples, image has no 4×u32 layout
+    let image = ZPNG_ImageData {
+        Buffer: ZPNG_Buffer {
+            Data: vec![0; (WIDTH * HEIGHT * 16) as usize],
+        },
+        BytesPerChannel: 4,
+        Channels: 4,
+        WidthPixels: WIDTH,
+        HeightPixels: HEIGHT,
+    };
+    let compressed = ZPNG_Compress(&image).unwrap();
+    match ZpngDecoder::new(Cursor::new(compressed.Data)) {
+        Err(ImageError::Unsupported(err)) => assert_eq!(
+            err.kind(),
+            UnsupportedErrorKind::Color(ExtendedColorType::Unknown(128))
+        ),
+        Err(err) => panic!("{:?}", err),
+        Ok(_) => panic!("decoded a 4×u32 image"),
+    }
+}
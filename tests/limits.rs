@@ -0,0 +1,133 @@
+//! `DecodeLimits` and headers that claim more than the payload holds
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+use zpng_rs_lib::*;
+
+fn BlankImage(width: u32, height: u32) -> ZPNG_ImageData {
+    ZPNG_ImageData {
+        Buffer: ZPNG_Buffer {
+            Data: vec![0; (width * height) as usize],
+        },
+        BytesPerChannel: 1,
+        Channels: 1,
+        WidthPixels: width,
+        HeightPixels: height,
+    }
+}
+
+fn Blank(width: u32, height: u32) -> ZPNG_Buffer {
+    ZPNG_Compress(&BlankImage(width, height)).unwrap()
+}
+
+#[test]
+fn header_larger_than_payload() {
+    // claims 65535×65535 RGBA 16 bit, followed by a few bytes that aren't a zstd frame
+    let mut data = vec![0xF8, 0xFB, 0xFF, 0xFF, 0xFF, 0xFF, 4, 2];
+    data.extend_from_slice(&[0; 10]);
+    match ZPNG_Decompress(&ZPNG_Buffer { Data: data }) {
+        Err(ZpngError::Zstd(_)) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn empty_payload() {
+    let compressed = Blank(64, 64);
+    let header = ZPNG_Buffer {
+        Data: compressed.Data[..ZPNG_HEADER_OVERHEAD_BYTES as usize].to_vec(),
+    };
+    match ZPNG_Decompress(&header) {
+        Err(ZpngError::Zstd(err)) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn limits() {
+    let compressed = Blank(1000, 1000);
+    assert!(ZPNG_Decompress(&compressed).is_ok());
+
+    let pixels = DecodeLimits {
+        MaxPixels: 999_999,
+        ..DecodeLimits::default()
+    };
+    match ZPNG_DecompressWithLimits(&compressed, &pixels) {
+        Err(ZpngError::LimitExceeded { Limit, Value, Max }) => {
+            assert_eq!((Limit, Value, Max), ("pixel count", 1_000_000, 999_999))
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    // the packing buffer and the output
+    let alloc = DecodeLimits {
+        MaxAlloc: 1_999_999,
+        ..DecodeLimits::default()
+    };
+    match ZPNG_DecompressWithLimits(&compressed, &alloc) {
+        Err(ZpngError::LimitExceeded { Limit, Value, .. }) => {
+            assert_eq!((Limit, Value), ("allocation", 2_000_000))
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    let ratio = DecodeLimits {
+        MaxRatio: 10,
+        ..DecodeLimits::default()
+    };
+    match ZPNG_DecompressWithLimits(&compressed, &ratio) {
+        Err(ZpngError::LimitExceeded {
+            Limit: "compression ratio",
+            ..
+        }) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn ratio_check_off() {
+    let compressed = Blank(1000, 1000);
+    let limits = DecodeLimits {
+        MaxRatio: u64::MAX,
+        ..DecodeLimits::default()
+    };
+    let decompressed = ZPNG_DecompressWithLimits(&compressed, &limits).unwrap();
+    assert_eq!(decompressed.Buffer.Data.len(), 1_000_000);
+}
+
+#[cfg(feature = "brotli")]
+fn Brotli(image: &ZPNG_ImageData) -> ZPNG_Buffer {
+    let options = CompressOptions {
+        EntropyBackend: EntropyBackend::Brotli,
+        Level: 11,
+        ..CompressOptions::default()
+    };
+    ZPNG_CompressWithOptions(image, &options).unwrap()
+}
+
+#[cfg(feature = "brotli")]
+#[test]
+fn brotli_beyond_the_default_ratio() {
+    let image = BlankImage(4000, 4000);
+    let compressed = Brotli(&image);
+    assert!(compressed.Data.len() < 4000 * 4000 / 65536);
+
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+}
+
+#[cfg(feature = "brotli")]
+#[test]
+fn brotli_claiming_more_than_it_holds() {
+    // 100000×100000 pixels in the header and the size prefix, 100×100 in the stream
+    let mut compressed = Brotli(&BlankImage(100, 100));
+    compressed.Data[4..8].copy_from_slice(&100_000u32.to_le_bytes());
+    compressed.Data[8..12].copy_from_slice(&100_000u32.to_le_bytes());
+    compressed.Data[16..24].copy_from_slice(&10_000_000_000u64.to_le_bytes());
+    match ZPNG_Decompress(&compressed) {
+        Err(ZpngError::Backend(EntropyBackend::Brotli, err)) => {
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof)
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
@@ -0,0 +1,29 @@
+//! `CompressOptions` change how hard zstd works, never what the file decodes to
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::TestImage;
+use zpng_rs_lib::*;
+
+#[test]
+fn same_output_for_any_thread_count() {
+    // several zstd jobs at the section size of level 1
+    let image = TestImage(2000, 1500, 3, 1);
+    let Compress = |threads| {
+        let options = CompressOptions {
+            Level: 1,
+            Threads: threads,
+            ..CompressOptions::default()
+        };
+        ZPNG_CompressWithOptions(&image, &options).unwrap().Data
+    };
+
+    let single = Compress(1);
+    for &threads in &[2, 3, 4, 8, 0] {
+        assert!(Compress(threads) == single, "{} threads", threads);
+    }
+    let decompressed = ZPNG_Decompress(&ZPNG_Buffer { Data: single }).unwrap();
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+}
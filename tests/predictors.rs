@@ -0,0 +1,122 @@
+//! Spatial predictors on every filter path, and the side table of the adaptive one
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::TestImage;
+use zpng_rs_lib::*;
+
+const PREDICTORS: [ZPNG_Predictor; 6] = [
+    ZPNG_Predictor::Left,
+    ZPNG_Predictor::Up,
+    ZPNG_Predictor::Average,
+    ZPNG_Predictor::Paeth,
+    ZPNG_Predictor::Med,
+    ZPNG_Predictor::Adaptive,
+];
+
+const FILTERS: [ZPNG_ColorFilter; 3] = [
+    ZPNG_ColorFilter::None,
+    ZPNG_ColorFilter::GbRg,
+    ZPNG_ColorFilter::YCoCgR,
+];
+
+/// Bands are 16 rows, so the last one is usually cut short
+const HEIGHTS: [u32; 6] = [1, 7, 16, 17, 33, 50];
+
+fn RoundTrip(image: &ZPNG_ImageData, options: &CompressOptions) -> ZPNG_Header {
+    let compressed = ZPNG_CompressWithOptions(image, options).unwrap();
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert!(
+        decompressed.Buffer.Data == image.Buffer.Data,
+        "{}x{} {:?}",
+        image.WidthPixels,
+        image.HeightPixels,
+        options
+    );
+    ZPNG_ReadHeader(&compressed.Data).unwrap()
+}
+
+#[test]
+fn every_predictor_on_every_path() {
+    // byte by byte for 8 and 16 bit channels, and 16 bit samples
+    let layouts = [
+        (1, 1, false),
+        (2, 1, false),
+        (3, 1, false),
+        (4, 1, false),
+        (8, 1, false),
+        (3, 2, false),
+        (1, 2, true),
+        (3, 2, true),
+        (4, 2, true),
+    ];
+    for &(channels, bytesPerChannel, filter16) in &layouts {
+        for &height in &HEIGHTS {
+            let image = TestImage(19, height, channels, bytesPerChannel);
+            for &colorFilter in &FILTERS {
+                for &predictor in &PREDICTORS {
+                    let options = CompressOptions {
+                        ColorFilter: colorFilter,
+                        Predictor: predictor,
+                        Filter16Bit: filter16,
+                        ..CompressOptions::default()
+                    };
+                    let header = RoundTrip(&image, &options);
+                    assert_eq!(header.Predictor, predictor);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn left_for_32_bit_samples() {
+    let options = CompressOptions {
+        Filter32Bit: true,
+        ..CompressOptions::default()
+    };
+    for &predictor in &PREDICTORS {
+        let image = TestImage(19, 17, 2, 4);
+        let header = RoundTrip(
+            &image,
+            &CompressOptions {
+                Predictor: predictor,
+                ..options
+            },
+        );
+        assert_eq!(header.Predictor, ZPNG_Predictor::Left);
+    }
+}
+
+#[test]
+fn corrupt_predictor_table() {
+    let image = TestImage(19, 37, 3, 1);
+    let options = CompressOptions {
+        Predictor: ZPNG_Predictor::Adaptive,
+        ..CompressOptions::default()
+    };
+    let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    let (head, frame) = compressed.Data.split_at(header.OverheadBytes());
+
+    let mut packing = vec![0; zstd_safe::get_frame_content_size(frame) as usize];
+    zstd_safe::decompress(&mut packing, frame).unwrap();
+    // one byte for each band of 16 rows after the filtered pixels, adaptive can't nest
+    let last = packing.len() - 1;
+    for &value in &[5, 6, 0xFF] {
+        packing[last] = value;
+        let mut data = head.to_vec();
+        let mut recompressed = vec![0; zstd_safe::compress_bound(packing.len())];
+        let size = zstd_safe::compress(&mut recompressed, &packing, 1).unwrap();
+        data.extend_from_slice(&recompressed[..size]);
+        match ZPNG_Decompress(&ZPNG_Buffer { Data: data }) {
+            Err(ZpngError::UnknownHeaderValue {
+                Field: "predictor table",
+                Value,
+            }) => assert_eq!(Value, value as u32),
+            other => panic!("{:?}", other.map(|image| image.WidthPixels)),
+        }
+    }
+}
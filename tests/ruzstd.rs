@@ -0,0 +1,159 @@
+//! The pure Rust zstd decoder has to give exactly what the C library gives.
+//!
+//! Run with `cargo test --features ruzstd`, zstd-safe is still needed to compress the images.
+#![cfg(all(feature = "ruzstd", feature = "zstd-safe"))]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::TestImage;
+use zpng_rs_lib::*;
+
+/// The zstd frame after the zpng header
+fn Payload(compressed: &ZPNG_Buffer) -> &[u8] {
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    &compressed.Data[header.OverheadBytes()..]
+}
+
+/// Decodes a frame with the C library
+fn CDecompress(frame: &[u8]) -> Vec<u8> {
+    let size = zstd_safe::get_frame_content_size(frame);
+    assert!(size != zstd_safe::CONTENTSIZE_ERROR && size != zstd_safe::CONTENTSIZE_UNKNOWN);
+    let mut output = vec![0; size as usize];
+    let written = zstd_safe::decompress(&mut output, frame).unwrap();
+    assert_eq!(written, output.len());
+    output
+}
+
+/// Decodes a frame with ruzstd
+fn RustDecompress(frame: &[u8]) -> Vec<u8> {
+    TryRustDecompress(frame).unwrap()
+}
+
+fn TryRustDecompress(mut frame: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind, Read};
+    let mut decoder = ruzstd::StreamingDecoder::new(&mut frame)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let mut output = Vec::new();
+    decoder.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+#[test]
+fn frames_decode_like_the_c_library() {
+    let layouts = [
+        (1, 1),
+        (2, 1),
+        (3, 1),
+        (4, 1),
+        (8, 1),
+        (1, 2),
+        (3, 2),
+        (4, 2),
+        (4, 4),
+    ];
+    for &(channels, bytesPerChannel) in &layouts {
+        for &(width, height) in &[(1, 1), (37, 23), (300, 200)] {
+            let image = TestImage(width, height, channels, bytesPerChannel);
+            for &level in &[-5, 1, 19] {
+                let options = CompressOptions {
+                    Level: level,
+                    Predictor: ZPNG_Predictor::Adaptive,
+                    ..Default::default()
+                };
+                let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+                let payload = Payload(&compressed);
+                assert_eq!(RustDecompress(payload), CDecompress(payload));
+
+                let decompressed = ZPNG_Decompress(&compressed).unwrap();
+                assert_eq!(decompressed.Buffer.Data, image.Buffer.Data);
+            }
+        }
+    }
+}
+
+#[test]
+fn every_filter_decodes_like_the_c_library() {
+    let image = TestImage(123, 77, 4, 1);
+    let filters = [
+        ZPNG_ColorFilter::None,
+        ZPNG_ColorFilter::GbRg,
+        ZPNG_ColorFilter::YCoCgR,
+    ];
+    let predictors = [
+        ZPNG_Predictor::Left,
+        ZPNG_Predictor::Up,
+        ZPNG_Predictor::Average,
+        ZPNG_Predictor::Paeth,
+        ZPNG_Predictor::Med,
+        ZPNG_Predictor::Adaptive,
+    ];
+    for &filter in &filters {
+        for &predictor in &predictors {
+            let options = CompressOptions {
+                ColorFilter: filter,
+                Predictor: predictor,
+                ..Default::default()
+            };
+            let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+            let payload = Payload(&compressed);
+            assert_eq!(RustDecompress(payload), CDecompress(payload));
+            assert_eq!(
+                ZPNG_Decompress(&compressed).unwrap().Buffer.Data,
+                image.Buffer.Data
+            );
+        }
+    }
+}
+
+#[test]
+fn large_multithreaded_frames() {
+    // several 128 KiB blocks, long distance matching and worker threads change the frame layout
+    let image = TestImage(1500, 700, 3, 2);
+    for &threads in &[1, 4] {
+        let options = CompressOptions {
+            Level: 3,
+            WindowLog: 24,
+            LongDistanceMatching: true,
+            Threads: threads,
+            ..Default::default()
+        };
+        let compressed = ZPNG_CompressWithOptions(&image, &options).unwrap();
+        let payload = Payload(&compressed);
+        assert_eq!(RustDecompress(payload), CDecompress(payload));
+        assert_eq!(
+            ZPNG_Decompress(&compressed).unwrap().Buffer.Data,
+            image.Buffer.Data
+        );
+    }
+}
+
+#[test]
+fn corrupt_frames_never_decode_differently() {
+    let image = TestImage(64, 48, 4, 1);
+    let compressed = ZPNG_Compress(&image).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    let (head, frame) = compressed.Data.split_at(header.OverheadBytes());
+
+    for offset in 0..frame.len() {
+        for &mask in &[0x01, 0x80, 0xFF] {
+            let mut corrupt = frame.to_vec();
+            corrupt[offset] ^= mask;
+
+            // either decoder may reject damage the other one misses, but they never disagree
+            let size = zstd_safe::get_frame_content_size(&corrupt);
+            if let Ok(rust) = TryRustDecompress(&corrupt) {
+                if size != zstd_safe::CONTENTSIZE_ERROR && size == rust.len() as u64 {
+                    let mut output = vec![0; rust.len()];
+                    if zstd_safe::decompress(&mut output, &corrupt) == Ok(output.len()) {
+                        assert_eq!(rust, output, "offset {} mask {:#x}", offset, mask);
+                    }
+                }
+            }
+
+            let mut data = head.to_vec();
+            data.extend_from_slice(&corrupt);
+            let _ = ZPNG_Decompress(&ZPNG_Buffer { Data: data });
+        }
+    }
+}
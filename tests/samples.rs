@@ -0,0 +1,123 @@
+//! 16 and 32 bit channels filtered as whole samples with `Filter16Bit`, `Filter32Bit` and YCoCg-R
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::TestImage;
+use zpng_rs_lib::*;
+
+const PREDICTORS: [ZPNG_Predictor; 6] = [
+    ZPNG_Predictor::Left,
+    ZPNG_Predictor::Up,
+    ZPNG_Predictor::Average,
+    ZPNG_Predictor::Paeth,
+    ZPNG_Predictor::Med,
+    ZPNG_Predictor::Adaptive,
+];
+
+fn RoundTrip(image: &ZPNG_ImageData, options: &CompressOptions) -> ZPNG_Header {
+    let compressed = ZPNG_CompressWithOptions(image, options).unwrap();
+    let decompressed = ZPNG_Decompress(&compressed).unwrap();
+    assert_eq!(decompressed.Channels, image.Channels);
+    assert_eq!(decompressed.BytesPerChannel, image.BytesPerChannel);
+    assert!(decompressed.Buffer.Data == image.Buffer.Data);
+    ZPNG_ReadHeader(&compressed.Data).unwrap()
+}
+
+#[test]
+fn gbrg_on_16_bit_rgb() {
+    for &channels in &[3, 4] {
+        let image = TestImage(37, 23, channels, 2);
+        for &predictor in PREDICTORS.iter() {
+            let options = CompressOptions {
+                ColorFilter: ZPNG_ColorFilter::GbRg,
+                Predictor: predictor,
+                Filter16Bit: true,
+                ..CompressOptions::default()
+            };
+            let header = RoundTrip(&image, &options);
+            assert_eq!(header.Flags, ZPNG_FLAG_16BIT_SAMPLES);
+            assert_eq!(header.ColorFilter, ZPNG_ColorFilter::GbRg);
+            assert_eq!(header.Predictor, predictor);
+        }
+    }
+}
+
+#[test]
+fn off_by_default() {
+    let image = TestImage(37, 23, 3, 2);
+    let header = RoundTrip(&image, &CompressOptions::default());
+    assert_eq!(header.Magic, ZPNG_HEADER_MAGIC);
+    assert_eq!(header.Flags, 0);
+}
+
+/// Floats across the whole range, including the values that don't compare equal to themselves
+fn FloatImage(width: u32, height: u32, channels: u8) -> ZPNG_ImageData {
+    let special = [
+        0.0,
+        -0.0,
+        f32::NAN,
+        -f32::NAN,
+        f32::INFINITY,
+        f32::NEG_INFINITY,
+        f32::MIN,
+        f32::MAX,
+    ];
+    let count = (width * height) as usize * channels as usize;
+    let Data = (0..count)
+        .map(|i| match i % 5 {
+            0 => special[i / 5 % special.len()],
+            1 => -(i as f32) * 0.37,
+            2 => (i as f32).sin() * 1e6,
+            _ => 1.0 / (i as f32 - 100.5),
+        })
+        .flat_map(|value| value.to_bits().to_le_bytes().to_vec())
+        .collect();
+    ZPNG_ImageData {
+        Buffer: ZPNG_Buffer { Data },
+        BytesPerChannel: 4,
+        Channels: channels,
+        WidthPixels: width,
+        HeightPixels: height,
+    }
+}
+
+#[test]
+fn f32_channels() {
+    let options = CompressOptions {
+        Filter32Bit: true,
+        ..CompressOptions::default()
+    };
+    for channels in 1..=4 {
+        let image = FloatImage(29, 17, channels);
+        // compares the bits, so NaN payloads and the sign of zero have to survive as well
+        let header = RoundTrip(&image, &options);
+        assert_eq!(header.Flags, ZPNG_FLAG_32BIT_SAMPLES);
+        assert_eq!(header.Channels, channels);
+        assert_eq!(header.BytesPerChannel, 4);
+    }
+}
+
+#[test]
+fn ycocg_on_every_layout() {
+    for &(channels, bytesPerChannel) in &[(3, 1), (4, 1), (3, 2), (4, 2)] {
+        let image = TestImage(37, 23, channels, bytesPerChannel);
+        for &filter16 in &[false, true] {
+            let options = CompressOptions {
+                ColorFilter: ZPNG_ColorFilter::YCoCgR,
+                Filter16Bit: filter16,
+                ..CompressOptions::default()
+            };
+            let header = RoundTrip(&image, &options);
+            assert_eq!(header.ColorFilter, ZPNG_ColorFilter::YCoCgR);
+            // 16 bit channels are filtered as samples even without Filter16Bit
+            let flags = if bytesPerChannel == 2 {
+                ZPNG_FLAG_16BIT_SAMPLES
+            } else {
+                0
+            };
+            assert_eq!(header.Flags, flags);
+        }
+    }
+}
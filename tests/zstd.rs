@@ -0,0 +1,51 @@
+//! zstd payloads the decoder has to accept besides what `ZPNG_Compress` writes
+//!
+//! Runs against the C library, and against ruzstd with `--features ruzstd`.
+#![cfg(feature = "zstd-safe")]
+#![allow(non_snake_case)]
+
+mod common;
+
+use common::TestImage;
+use zpng_rs_lib::*;
+
+#[test]
+fn bare_block_and_skippable_frame() {
+    let image = TestImage(20, 10, 3, 1);
+    let compressed = ZPNG_Compress(&image).unwrap();
+    let header = ZPNG_ReadHeader(&compressed.Data).unwrap();
+    let (head, frame) = compressed.Data.split_at(header.OverheadBytes());
+
+    // a skippable frame in front is ignored
+    let mut skipped = head.to_vec();
+    skipped.extend_from_slice(&[0x5A, 0x2A, 0x4D, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+    skipped.extend_from_slice(frame);
+    let decompressed = ZPNG_Decompress(&ZPNG_Buffer { Data: skipped }).unwrap();
+    assert_eq!(decompressed.Buffer.Data, image.Buffer.Data);
+
+    // Frame_Header_Descriptor of a frame with a single compressed block, as written for small
+    // images
+    let descriptor = frame[4];
+    let singleSegment = (descriptor >> 5) & 1 == 1;
+    let contentSizeBytes = match descriptor >> 6 {
+        0 => singleSegment as usize,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let dictionaryBytes = [0, 1, 2, 4][(descriptor & 3) as usize];
+    let blockStart = 5 + !singleSegment as usize + dictionaryBytes + contentSizeBytes;
+    let block = u32::from_le_bytes([
+        frame[blockStart],
+        frame[blockStart + 1],
+        frame[blockStart + 2],
+        0,
+    ]);
+    assert_eq!(block & 1, 1, "last block");
+    assert_eq!((block >> 1) & 3, 2, "compressed block");
+
+    let mut bare = head.to_vec();
+    bare.extend_from_slice(&frame[blockStart + 3..blockStart + 3 + (block >> 3) as usize]);
+    let decompressed = ZPNG_Decompress(&ZPNG_Buffer { Data: bare }).unwrap();
+    assert_eq!(decompressed.Buffer.Data, image.Buffer.Data);
+}